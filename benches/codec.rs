@@ -0,0 +1,136 @@
+//! Answers Binding requests the way the UDP listeners do, through the request pipeline with
+//! the response buffer of the last request, and counts the heap allocations per request.
+//! `handle_message_in` on its own must not allocate at all; it and the owned
+//! `DecodedMessage` path are measured alongside for comparison.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use stun::attributes::{Attribute, AttributeRegistry, Software};
+use stun::handlers::{handle_message, handle_message_in};
+use stun::message::{Class, DecodedMessage, Method, StunHeader, StunMessageRef};
+use stun::pipeline::{RequestContext, Transport};
+use stun::stunserver::ListenerContext;
+
+/// Counts allocations, reallocations included, on top of the system allocator.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const REQUESTS: usize = 1000;
+
+/// A Binding request carrying SOFTWARE, as most clients send it.
+fn binding_request() -> Vec<u8> {
+    let software = Software::new("client/1.0").serialize();
+    let mut request = StunHeader::new(
+        Method::BINDING,
+        Class::Request,
+        software.len() as u16,
+        [7; 12],
+    )
+    .serialize();
+    request.extend(software);
+    request
+}
+
+/// Allocations per call of `handle`, after a first call to warm up buffers.
+fn allocations_per_request(mut handle: impl FnMut()) -> f64 {
+    handle();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..REQUESTS {
+        handle();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / REQUESTS as f64
+}
+
+fn bench_binding(c: &mut Criterion) {
+    let request = binding_request();
+    let address: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+    let local_address: SocketAddr = "198.51.100.1:3478".parse().unwrap();
+    let software = Some("stun/0.1.0");
+
+    let mut owned = || {
+        let decoded = DecodedMessage::decode(black_box(&request)).unwrap();
+        black_box(decoded.attribute(0x8022));
+        let response = handle_message(&request, address, local_address, software, None);
+        black_box(response.unwrap().into_bytes());
+    };
+    let context = RequestContext {
+        peer: address,
+        local_address,
+        transport: Transport::Udp,
+        tls: None,
+        shutting_down: false,
+    };
+    let mut buffer = Vec::new();
+    let mut borrowed = || {
+        let message = StunMessageRef::parse(black_box(&request)).unwrap();
+        black_box(message.attribute(0x8022));
+        let response = handle_message_in(
+            std::mem::take(&mut buffer),
+            &request,
+            &context,
+            software,
+            None,
+            &AttributeRegistry::new(),
+            None,
+        );
+        buffer = black_box(response.unwrap().into_bytes());
+    };
+
+    // Logging, metrics and rate limiting, then the built-in Binding handling.
+    let listener = ListenerContext::default();
+    let mut buffer = Vec::new();
+    let mut pipeline = || {
+        let response = futures::executor::block_on(listener.pipeline.handle(
+            black_box(&request),
+            &context,
+            std::mem::take(&mut buffer),
+        ));
+        buffer = black_box(response.unwrap().into_bytes());
+    };
+
+    let owned_allocations = allocations_per_request(&mut owned);
+    let borrowed_allocations = allocations_per_request(&mut borrowed);
+    let pipeline_allocations = allocations_per_request(&mut pipeline);
+    // As the server runs by default: transaction spans recorded at info level.
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::sink)
+        .finish();
+    let logged_allocations =
+        tracing::subscriber::with_default(subscriber, || allocations_per_request(&mut pipeline));
+    println!(
+        "allocations per Binding request: pipeline {} ({} with info logging), \
+         handle_message_in {}, owned {}",
+        pipeline_allocations, logged_allocations, borrowed_allocations, owned_allocations
+    );
+    assert_eq!(borrowed_allocations, 0.0);
+
+    let mut group = c.benchmark_group("binding");
+    group.bench_function("pipeline", |b| b.iter(&mut pipeline));
+    group.bench_function("owned", |b| b.iter(&mut owned));
+    group.bench_function("zero_copy", |b| b.iter(&mut borrowed));
+    group.finish();
+}
+
+criterion_group!(benches, bench_binding);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+//! Compares the per-datagram tokio UDP loop with the batched recvmmsg/sendmmsg loop on
+//! loopback. Each iteration sends a burst of Binding requests and waits for every response.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+use stun::batch;
+use stun::shutdown::Shutdown;
+use stun::stunserver::{udp_loop_unbatched, ListenerContext};
+use tokio::runtime::Runtime;
+
+const BURST: usize = 64;
+
+const BINDING_REQUEST: [u8; 20] = [
+    0, 1, 0, 0, 33, 18, 164, 66, 28, 54, 51, 69, 185, 194, 210, 171, 179, 118, 57, 253,
+];
+
+fn start_server(runtime: &Runtime, batched: bool) -> SocketAddr {
+    let socket = runtime
+        .block_on(tokio::net::UdpSocket::bind("127.0.0.1:0"))
+        .unwrap();
+    let address = socket.local_addr().unwrap();
+    runtime.spawn(async move {
+        if batched {
+            let _ =
+                batch::udp_loop(&socket, &ListenerContext::default(), &Shutdown::default()).await;
+        } else {
+            let _ = udp_loop_unbatched(&socket, &ListenerContext::default(), &Shutdown::default())
+                .await;
+        }
+    });
+    address
+}
+
+fn round_trip_burst(client: &UdpSocket, server: SocketAddr) {
+    let mut buffer = [0_u8; 1024];
+    for _ in 0..BURST {
+        client.send_to(&BINDING_REQUEST, server).unwrap();
+    }
+    for _ in 0..BURST {
+        client.recv_from(&mut buffer).unwrap();
+    }
+}
+
+fn bench_udp_io(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("udp_loopback");
+    group.throughput(Throughput::Elements(BURST as u64));
+
+    for &(name, batched) in &[("unbatched", false), ("batched", true)] {
+        let server = start_server(&runtime, batched);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        group.bench_function(name, |b| b.iter(|| round_trip_burst(&client, server)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_udp_io);
+criterion_main!(benches);
@@ -0,0 +1,244 @@
+//! Per-listener allow/deny lists of source networks.
+//!
+//! A source matching any `deny` network is refused. Otherwise, when `allow` is not empty,
+//! the source must match one of its networks. Lists can be replaced while the server runs.
+use crate::config::{AccessConfig, Config};
+use crate::ratelimit::canonical;
+use crate::stunserver::ListenAddress;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// An IPv4 or IPv6 network, written `address/prefix`. A bare address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = canonical(address);
+        address.is_ipv4() == self.address.is_ipv4()
+            && mask(address, self.prefix) == to_bits(self.address)
+    }
+}
+
+fn to_bits(address: IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(v4) => u128::from(u32::from(v4)),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+fn width(address: IpAddr) -> u8 {
+    if address.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+/// Keeps the first `prefix` bits of `address`.
+fn mask(address: IpAddr, prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => {
+            to_bits(address) & ((u128::MAX >> (128 - width(address))) << (width(address) - prefix))
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match input.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (input, None),
+        };
+        let address = canonical(
+            address
+                .parse::<IpAddr>()
+                .map_err(|e| format!("invalid network {:?}: {}", input, e))?,
+        );
+        let bits = width(address);
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("invalid prefix length in {:?}", input))?,
+            None => bits,
+        };
+        if mask(address, prefix) != to_bits(address) {
+            return Err(format!("{:?} has bits set beyond the prefix length", input));
+        }
+        Ok(Cidr { address, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cidr = String::deserialize(deserializer)?;
+        cidr.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+/// The access list of one listener.
+#[derive(Debug, Default)]
+pub struct AccessList {
+    rules: RwLock<Rules>,
+}
+
+impl AccessList {
+    pub fn new(config: &AccessConfig) -> AccessList {
+        let access_list = AccessList::default();
+        access_list.update(config);
+        access_list
+    }
+
+    /// Whether `source` may use the listener.
+    pub fn permits(&self, source: IpAddr) -> bool {
+        let rules = self.rules.read().unwrap();
+        !rules.deny.iter().any(|cidr| cidr.contains(source))
+            && (rules.allow.is_empty() || rules.allow.iter().any(|cidr| cidr.contains(source)))
+    }
+
+    pub fn update(&self, config: &AccessConfig) {
+        *self.rules.write().unwrap() = Rules {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+        };
+    }
+}
+
+/// The access lists of every listener in a configuration, so they can be reloaded together.
+#[derive(Debug, Default)]
+pub struct AccessLists {
+    lists: Vec<(ListenAddress, Arc<AccessList>)>,
+}
+
+impl AccessLists {
+    pub fn new(config: &Config) -> AccessLists {
+        AccessLists {
+            lists: config
+                .listen
+                .iter()
+                .map(|listen| {
+                    (
+                        *listen,
+                        Arc::new(AccessList::new(&config.access_for(listen))),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// The list of `listen`; listeners not known when this was created allow everyone.
+    pub fn get(&self, listen: &ListenAddress) -> Arc<AccessList> {
+        self.lists
+            .iter()
+            .find(|(address, _)| address == listen)
+            .map(|(_, list)| list.clone())
+            .unwrap_or_default()
+    }
+
+    /// Replaces every list with the one in `config`. Listeners added to `config` since are
+    /// ignored; they only take effect after a restart.
+    pub fn reload(&self, config: &Config) {
+        for (listen, list) in &self.lists {
+            list.update(&config.access_for(listen));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access_list(allow: &[&str], deny: &[&str]) -> AccessList {
+        let parse = |cidrs: &[&str]| cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect();
+        AccessList::new(&AccessConfig {
+            allow: parse(allow),
+            deny: parse(deny),
+        })
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::a01:203".parse().unwrap()));
+        assert_eq!(
+            "2001:db8::1".parse::<Cidr>().unwrap().to_string(),
+            "2001:db8::1/128"
+        );
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("192.0.2.1".parse().unwrap()));
+        assert!("10.0.0.1/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.org/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_deny_overrides_allow() {
+        let list = access_list(&["10.0.0.0/8", "2001:db8::/32"], &["10.66.0.0/16"]);
+        assert!(list.permits("10.1.2.3".parse().unwrap()));
+        assert!(list.permits("2001:db8::7".parse().unwrap()));
+        assert!(!list.permits("10.66.0.1".parse().unwrap()));
+        assert!(!list.permits("192.0.2.1".parse().unwrap()));
+
+        let list = access_list(&[], &["192.0.2.0/24"]);
+        assert!(list.permits("198.51.100.1".parse().unwrap()));
+        assert!(!list.permits("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_reload_replaces_lists() {
+        let mut config = Config::parse(
+            r#"
+            listen = ["127.0.0.1:3478/udp", "127.0.0.1:3479/udp"]
+            [access."127.0.0.1:3478/udp"]
+            allow = ["10.0.0.0/8"]
+            "#,
+        )
+        .unwrap();
+        let lists = AccessLists::new(&config);
+        let first = lists.get(&config.listen[0]);
+        let second = lists.get(&config.listen[1]);
+        let source = "192.0.2.1".parse().unwrap();
+        assert!(!first.permits(source));
+        assert!(second.permits(source));
+
+        config.access.clear();
+        config.access.insert(
+            "127.0.0.1:3479/udp".to_owned(),
+            AccessConfig {
+                allow: Vec::new(),
+                deny: vec!["192.0.2.0/24".parse().unwrap()],
+            },
+        );
+        lists.reload(&config);
+        assert!(first.permits(source));
+        assert!(!second.permits(source));
+    }
+}
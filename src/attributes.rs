@@ -1,6 +1,9 @@
 use crate::message::MAGIC_COOKIE;
 use byteorder::{BigEndian, ByteOrder};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub const ERROR_CODE: u16 = 0x0009;
 pub const MAPPED_ADDRESS: u16 = 0x0001;
@@ -9,10 +12,16 @@ pub const MESSAGE_INTEGRITY: u16 = 0x0008;
 pub const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
 pub const REALM: u16 = 0x0014;
 pub const NONCE: u16 = 0x0015;
+pub const MESSAGE_INTEGRITY_SHA256: u16 = 0x001C;
+pub const PASSWORD_ALGORITHM: u16 = 0x001D;
+pub const USERHASH: u16 = 0x001E;
 pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const PASSWORD_ALGORITHMS: u16 = 0x8002;
+pub const ALTERNATE_DOMAIN: u16 = 0x8003;
 pub const SOFTWARE: u16 = 0x8022;
 pub const ALTERNATE_SERVER: u16 = 0x8023;
 pub const FINGERPRINT: u16 = 0x8028;
+pub const RESPONSE_ORIGIN: u16 = 0x802B;
 
 pub enum AttributeEnum {
     ErrorCode(ErrorCode),
@@ -21,13 +30,47 @@ pub enum AttributeEnum {
     UnknownAttributes(UnknownAttributes),
 }
 impl Attribute for AttributeEnum {
-    fn serialize(&self) -> Vec<u8> {
-        return Vec::new();
+    fn encoded_length(&self) -> usize {
+        match self {
+            AttributeEnum::ErrorCode(attribute) => attribute.encoded_length(),
+            AttributeEnum::MappedAddress(attribute) => attribute.encoded_length(),
+            AttributeEnum::XorMappedAddress(attribute) => attribute.encoded_length(),
+            AttributeEnum::UnknownAttributes(attribute) => attribute.encoded_length(),
+        }
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        match self {
+            AttributeEnum::ErrorCode(attribute) => attribute.encode(buffer),
+            AttributeEnum::MappedAddress(attribute) => attribute.encode(buffer),
+            AttributeEnum::XorMappedAddress(attribute) => attribute.encode(buffer),
+            AttributeEnum::UnknownAttributes(attribute) => attribute.encode(buffer),
+        }
     }
 }
 
 pub trait Attribute: Send {
-    fn serialize(&self) -> Vec<u8>;
+    /// Bytes the encoded attribute takes: its header, value and padding.
+    fn encoded_length(&self) -> usize;
+
+    /// Writes the attribute to `buffer`, which is `encoded_length` bytes long.
+    fn encode(&self, buffer: &mut [u8]);
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut stun_attribute = vec![0_u8; self.encoded_length()];
+        self.encode(&mut stun_attribute);
+        stun_attribute
+    }
+}
+
+impl<A: Attribute + ?Sized> Attribute for Box<A> {
+    fn encoded_length(&self) -> usize {
+        (**self).encoded_length()
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        (**self).encode(buffer)
+    }
 }
 
 pub struct ErrorCode {
@@ -44,23 +87,23 @@ impl ErrorCode {
         ErrorCode {
             type_: ERROR_CODE,
             length: (4 + reason_phrase.len()) as u16,
-            status_code: status_code,
-            reason_phrase: reason_phrase,
+            status_code,
+            reason_phrase,
         }
     }
 }
 impl Attribute for ErrorCode {
-    fn serialize(&self) -> Vec<u8> {
-        let mut stun_attribute: Vec<u8> = vec![0 as u8; 8];
-
-        BigEndian::write_u16(&mut stun_attribute[0..2], self.type_);
-        BigEndian::write_u16(&mut stun_attribute[2..4], self.length);
-        BigEndian::write_u32(&mut stun_attribute[4..8], self.status_code);
-        stun_attribute.append(&mut self.reason_phrase.clone().into_bytes());
-
-        add_padding(self.length, &mut stun_attribute);
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
 
-        return stun_attribute;
+    fn encode(&self, buffer: &mut [u8]) {
+        let value = encode_header(buffer, self.type_, self.length);
+        value[0..2].copy_from_slice(&[0, 0]);
+        // The hundreds digit goes in the class bits, the rest in the number (RFC 5389 15.6).
+        value[2] = (self.status_code / 100) as u8 & 0x07;
+        value[3] = (self.status_code % 100) as u8;
+        value[4..].copy_from_slice(self.reason_phrase.as_bytes());
     }
 }
 
@@ -76,46 +119,21 @@ pub struct MappedAddress {
 
 impl MappedAddress {
     pub fn new(address: SocketAddr) -> Self {
-        let len: u16;
-
-        match address.ip() {
-            IpAddr::V4(_ip) => {
-                len = 8;
-            }
-            IpAddr::V6(_ip) => {
-                len = 20;
-            }
-        }
-
-        return MappedAddress {
+        MappedAddress {
             type_: MAPPED_ADDRESS,
-            length: len,
-            address: address,
-        };
+            length: address_length(address),
+            address,
+        }
     }
 }
 
 impl Attribute for MappedAddress {
-    fn serialize(&self) -> Vec<u8> {
-        let mut stun_attribute: Vec<u8> = vec![0 as u8; 8];
-
-        BigEndian::write_u16(&mut stun_attribute[0..2], self.type_);
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
 
-        BigEndian::write_u16(&mut stun_attribute[2..4], self.length);
-        match self.address.ip() {
-            IpAddr::V4(ip) => {
-                BigEndian::write_u16(&mut stun_attribute[4..6], 0x01);
-                BigEndian::write_u16(&mut stun_attribute[6..8], self.address.port());
-                stun_attribute.append(&mut ip.octets().to_vec());
-            }
-            IpAddr::V6(ip) => {
-                BigEndian::write_u16(&mut stun_attribute[4..6], 0x02);
-                BigEndian::write_u16(&mut stun_attribute[6..8], self.address.port());
-                stun_attribute.append(&mut ip.octets().to_vec());
-            }
-        }
-        //add_padding(self.length, &mut stun_attribute);
-        return stun_attribute;
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_address(buffer, self.type_, self.length, self.address)
     }
 }
 //-----
@@ -130,64 +148,289 @@ pub struct XorMappedAddress {
 
 impl XorMappedAddress {
     pub fn new(addr: SocketAddr, transaction_id: &[u8]) -> Self {
-        let leng: u16;
-        let xor_port: u16 = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
-        let address: SocketAddr;
-        match addr.ip() {
-            IpAddr::V4(ip) => {
-                leng = 8;
-                let mut value = [0 as u8; 4];
-                for i in 0..4 {
-                    value[i] = ip.octets()[i] ^ ((MAGIC_COOKIE << 8 * i) >> 24) as u8;
-                }
-                address = SocketAddr::new(
-                    IpAddr::V4(Ipv4Addr::new(value[0], value[1], value[2], value[3])),
-                    xor_port,
-                );
-            }
-            IpAddr::V6(ip) => {
-                leng = 20;
-
-                let mut value = [0 as u8; 16];
-                for i in 0..4 {
-                    value[i] = ip.octets()[i] ^ ((MAGIC_COOKIE << 8 * i) >> 24) as u8;
-                }
-                for i in 4..16 {
-                    value[i] = ip.octets()[i] ^ (transaction_id[i - 4]);
-                }
-                let addr = From::from(value);
-                address = SocketAddr::new(IpAddr::V6(addr), xor_port);
-            }
-        }
-
-        return XorMappedAddress {
+        XorMappedAddress {
             type_: XOR_MAPPED_ADDRESS,
-            length: leng,
-            address: address,
-        };
+            length: address_length(addr),
+            address: xor_address(addr, transaction_id),
+        }
     }
 }
 impl Attribute for XorMappedAddress {
-    fn serialize(&self) -> Vec<u8> {
-        let mut stun_attribute: Vec<u8> = vec![0 as u8; 8];
-
-        BigEndian::write_u16(&mut stun_attribute[0..2], self.type_);
-        BigEndian::write_u16(&mut stun_attribute[2..4], self.length);
-        match self.address.ip() {
-            IpAddr::V4(ip) => {
-                BigEndian::write_u16(&mut stun_attribute[4..6], 0x01);
-                BigEndian::write_u16(&mut stun_attribute[6..8], self.address.port());
-                stun_attribute.append(&mut ip.octets().to_vec());
-            }
-            IpAddr::V6(ip) => {
-                BigEndian::write_u16(&mut stun_attribute[4..6], 0x02);
-                BigEndian::write_u16(&mut stun_attribute[6..8], self.address.port());
-                stun_attribute.append(&mut ip.octets().to_vec());
-            }
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_address(buffer, self.type_, self.length, self.address)
+    }
+}
+
+//-----
+
+/// RESPONSE-ORIGIN (RFC 5780): the local address the response was sent from.
+pub struct ResponseOrigin {
+    //generell attribute:
+    type_: u16,
+    length: u16,
+    //Spesielt til ResponseOrigin:
+    address: SocketAddr,
+}
+
+impl ResponseOrigin {
+    pub fn new(address: SocketAddr) -> Self {
+        ResponseOrigin {
+            type_: RESPONSE_ORIGIN,
+            length: address_length(address),
+            address,
+        }
+    }
+}
+
+impl Attribute for ResponseOrigin {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_address(buffer, self.type_, self.length, self.address)
+    }
+}
+
+//-----
+
+/// ALTERNATE-SERVER: the server a client redirected with 300 Try Alternate should use.
+pub struct AlternateServer {
+    //generell attribute:
+    type_: u16,
+    length: u16,
+    //Spesielt til AlternateServer:
+    address: SocketAddr,
+}
+
+impl AlternateServer {
+    pub fn new(address: SocketAddr) -> Self {
+        AlternateServer {
+            type_: ALTERNATE_SERVER,
+            length: address_length(address),
+            address,
+        }
+    }
+}
+
+impl Attribute for AlternateServer {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_address(buffer, self.type_, self.length, self.address)
+    }
+}
+
+//-----
+
+/// REALM, NONCE and other attributes whose value is text.
+pub struct Text {
+    //generell attribute:
+    type_: u16,
+    length: u16,
+    //Spesielt til Text:
+    text: String,
+}
+
+impl Text {
+    pub fn realm(realm: String) -> Self {
+        Text::new(REALM, realm)
+    }
+
+    pub fn nonce(nonce: String) -> Self {
+        Text::new(NONCE, nonce)
+    }
+
+    pub fn username(username: String) -> Self {
+        Text::new(USERNAME, username)
+    }
+
+    fn new(type_: u16, text: String) -> Self {
+        Text {
+            type_,
+            length: text.len() as u16,
+            text,
+        }
+    }
+}
+
+impl Attribute for Text {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_header(buffer, self.type_, self.length).copy_from_slice(self.text.as_bytes());
+    }
+}
+
+//-----
+
+/// MESSAGE-INTEGRITY or MESSAGE-INTEGRITY-SHA256, holding an HMAC computed by the caller.
+pub struct MessageIntegrity {
+    //generell attribute:
+    type_: u16,
+    length: u16,
+    //Spesielt til MessageIntegrity:
+    hmac: Vec<u8>,
+}
+
+impl MessageIntegrity {
+    /// `type_` is MESSAGE_INTEGRITY or MESSAGE_INTEGRITY_SHA256.
+    pub fn new(type_: u16, hmac: Vec<u8>) -> Self {
+        MessageIntegrity {
+            type_,
+            length: hmac.len() as u16,
+            hmac,
         }
+    }
+}
+
+impl Attribute for MessageIntegrity {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
 
-        //add_padding(self.length, &mut stun_attribute);
-        return stun_attribute;
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_header(buffer, self.type_, self.length).copy_from_slice(&self.hmac);
+    }
+}
+
+pub struct Fingerprint {
+    //generell attribute:
+    type_: u16,
+    length: u16,
+    //Spesielt til Fingerprint:
+    crc: u32,
+}
+
+impl Fingerprint {
+    /// `crc` is the CRC-32 of the message, XOR-ed with 0x5354554E.
+    pub fn new(crc: u32) -> Self {
+        Fingerprint {
+            type_: FINGERPRINT,
+            length: 4,
+            crc,
+        }
+    }
+}
+
+impl Attribute for Fingerprint {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        BigEndian::write_u32(encode_header(buffer, self.type_, self.length), self.crc);
+    }
+}
+
+//-----
+
+/// PASSWORD-ALGORITHMS (RFC 8489): the algorithm numbers the server offers, none of which
+/// take parameters.
+pub struct PasswordAlgorithms {
+    //generell attribute:
+    type_: u16,
+    length: u16,
+    //Spesielt til PasswordAlgorithms:
+    algorithms: Vec<u16>,
+}
+
+impl PasswordAlgorithms {
+    pub fn new(algorithms: Vec<u16>) -> Self {
+        PasswordAlgorithms {
+            type_: PASSWORD_ALGORITHMS,
+            length: (algorithms.len() * 4) as u16,
+            algorithms,
+        }
+    }
+}
+
+impl Attribute for PasswordAlgorithms {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        let value = encode_header(buffer, self.type_, self.length);
+        for (index, algorithm) in self.algorithms.iter().enumerate() {
+            // Each algorithm is followed by a zero parameter length.
+            BigEndian::write_u16(&mut value[index * 4..], *algorithm);
+            BigEndian::write_u16(&mut value[index * 4 + 2..], 0);
+        }
+    }
+}
+
+//-----
+
+/// ALTERNATE-DOMAIN (RFC 8489): the name in the certificate of the ALTERNATE-SERVER a TLS
+/// client is redirected to.
+pub struct AlternateDomain {
+    //generell attribute:
+    type_: u16,
+    length: u16,
+    //Spesielt til AlternateDomain:
+    domain: String,
+}
+
+impl AlternateDomain {
+    /// `domain` must be shorter than 256 bytes.
+    pub fn new(domain: String) -> Self {
+        AlternateDomain {
+            type_: ALTERNATE_DOMAIN,
+            length: domain.len() as u16,
+            domain,
+        }
+    }
+}
+
+impl Attribute for AlternateDomain {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_header(buffer, self.type_, self.length).copy_from_slice(self.domain.as_bytes());
+    }
+}
+
+//-----
+
+/// SOFTWARE: the name and version of the agent that sent the message.
+pub struct Software<'a> {
+    //generell attribute:
+    type_: u16,
+    length: u16,
+    //Spesielt til Software:
+    description: Cow<'a, str>,
+}
+
+impl<'a> Software<'a> {
+    /// `description` should be fewer than 128 characters (RFC 5389 15.10).
+    pub fn new(description: impl Into<Cow<'a, str>>) -> Self {
+        let description = description.into();
+        Software {
+            type_: SOFTWARE,
+            length: description.len() as u16,
+            description,
+        }
+    }
+}
+
+impl Attribute for Software<'_> {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_header(buffer, self.type_, self.length).copy_from_slice(self.description.as_bytes());
     }
 }
 
@@ -212,25 +455,239 @@ impl UnknownAttributes {
 }
 
 impl Attribute for UnknownAttributes {
-    fn serialize(&self) -> Vec<u8> {
-        let mut stun_attribute: Vec<u8> = vec![0 as u8; 4];
-        BigEndian::write_u16(&mut stun_attribute[0..2], self.type_);
-        BigEndian::write_u16(&mut stun_attribute[2..4], self.length);
-        for &attribute in &self.attributes {
-            let mut vec = vec![0 as u8; 2];
-            BigEndian::write_u16(&mut vec[0..2], attribute);
-            stun_attribute.append(&mut vec);
+    fn encoded_length(&self) -> usize {
+        padded_length(self.length)
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        let value = encode_header(buffer, self.type_, self.length);
+        for (index, attribute) in self.attributes.iter().enumerate() {
+            BigEndian::write_u16(&mut value[index * 2..], *attribute);
         }
+    }
+}
 
-        add_padding(self.length, &mut stun_attribute);
-        return stun_attribute;
+/// Applies the XOR-MAPPED-ADDRESS obfuscation. XOR is its own inverse, so the same
+/// function encodes and decodes.
+fn xor_address(address: SocketAddr, transaction_id: &[u8]) -> SocketAddr {
+    let xor_port: u16 = address.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            let mut value = ip.octets();
+            for (octet, cookie) in value.iter_mut().zip(&MAGIC_COOKIE.to_be_bytes()) {
+                *octet ^= cookie;
+            }
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::from(value)), xor_port)
+        }
+        IpAddr::V6(ip) => {
+            let mut value = ip.octets();
+            let cookie = MAGIC_COOKIE.to_be_bytes();
+            for (octet, mask) in value
+                .iter_mut()
+                .zip(cookie.iter().chain(transaction_id.iter()))
+            {
+                *octet ^= mask;
+            }
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(value)), xor_port)
+        }
     }
 }
 
-fn add_padding(length: u16, stun_attribute: &mut Vec<u8>) {
-    if length % 4 != 0 {
-        for _i in 0..(4 - (length % 4)) {
-            stun_attribute.push(0);
+/// Reads the value of a MAPPED-ADDRESS style attribute (family, port, address).
+pub fn decode_address(value: &[u8]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let port = BigEndian::read_u16(&value[2..4]);
+    match (BigEndian::read_u16(&value[0..2]), value.len()) {
+        (0x01, 8) => {
+            let mut octets = [0_u8; 4];
+            octets.copy_from_slice(&value[4..8]);
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port))
+        }
+        (0x02, 20) => {
+            let mut octets = [0_u8; 16];
+            octets.copy_from_slice(&value[4..20]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        _ => None,
+    }
+}
+
+/// Reads the value of an XOR-MAPPED-ADDRESS attribute.
+pub fn decode_xor_address(value: &[u8], transaction_id: &[u8]) -> Option<SocketAddr> {
+    decode_address(value).map(|address| xor_address(address, transaction_id))
+}
+
+/// An attribute this crate does not define, such as a vendor one. Implement it for the
+/// decoded value, wrap values in `Custom` to add them to messages and read them back with
+/// `get` on a received message.
+pub trait CustomAttribute: fmt::Debug + Send + Sized + 'static {
+    /// The attribute type number.
+    const TYPE: u16;
+    /// The name shown when messages are decoded, e.g. "TENANT-ID".
+    const NAME: &'static str;
+
+    /// Decodes the attribute value, `None` when it is malformed.
+    fn decode(value: &[u8]) -> Option<Self>;
+
+    /// The length of the encoded value, without padding.
+    fn value_length(&self) -> u16;
+
+    /// Writes the value to `value`, which is `value_length` bytes long.
+    fn encode_value(&self, value: &mut [u8]);
+}
+
+/// A `CustomAttribute` value added to a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Custom<A>(pub A);
+
+impl<A: CustomAttribute> Attribute for Custom<A> {
+    fn encoded_length(&self) -> usize {
+        padded_length(self.0.value_length())
+    }
+
+    fn encode(&self, buffer: &mut [u8]) {
+        let value = encode_header(buffer, A::TYPE, self.0.value_length());
+        self.0.encode_value(value);
+    }
+}
+
+/// What is known about a registered custom attribute type.
+#[derive(Debug, Clone, Copy)]
+struct Registered {
+    name: &'static str,
+    describe: fn(&[u8]) -> Option<String>,
+}
+
+/// The custom attribute types a server understands on top of the built-in ones, added with
+/// `StunServerBuilder::attribute`.
+#[derive(Debug, Clone, Default)]
+pub struct AttributeRegistry {
+    registered: BTreeMap<u16, Registered>,
+}
+
+impl AttributeRegistry {
+    pub fn new() -> Self {
+        AttributeRegistry::default()
+    }
+
+    /// Registers `A`, so that its type counts as understood in the 420 Unknown Attribute
+    /// check, and `name` and `describe` know it.
+    pub fn register<A: CustomAttribute>(&mut self) {
+        fn describe<A: CustomAttribute>(value: &[u8]) -> Option<String> {
+            A::decode(value).map(|attribute| format!("{:?}", attribute))
+        }
+        self.registered.insert(
+            A::TYPE,
+            Registered {
+                name: A::NAME,
+                describe: describe::<A>,
+            },
+        );
+    }
+
+    /// Whether a comprehension-required attribute of this type is built in or registered.
+    pub fn is_understood(&self, type_: u16) -> bool {
+        is_understood(type_) || self.registered.contains_key(&type_)
+    }
+
+    /// Like `attribute_name`, with the names of registered types.
+    pub fn name(&self, type_: u16) -> &'static str {
+        match self.registered.get(&type_) {
+            Some(registered) => registered.name,
+            None => attribute_name(type_),
+        }
+    }
+
+    /// The value of a registered attribute, formatted with its `Debug`.
+    pub fn describe(&self, type_: u16, value: &[u8]) -> Option<String> {
+        (self.registered.get(&type_)?.describe)(value)
+    }
+}
+
+/// Comprehension-required attributes (types below 0x8000) must be understood by the receiver,
+/// or the request is rejected with 420 Unknown Attribute.
+pub fn is_comprehension_required(type_: u16) -> bool {
+    type_ < 0x8000
+}
+
+/// Whether a comprehension-required attribute of this type is one the crate implements.
+pub fn is_understood(type_: u16) -> bool {
+    matches!(
+        type_,
+        MAPPED_ADDRESS
+            | USERNAME
+            | MESSAGE_INTEGRITY
+            | ERROR_CODE
+            | UNKNOWN_ATTRIBUTES
+            | REALM
+            | NONCE
+            | MESSAGE_INTEGRITY_SHA256
+            | PASSWORD_ALGORITHM
+            | USERHASH
+            | XOR_MAPPED_ADDRESS
+    )
+}
+
+/// Human readable name of an attribute type, as used in the RFCs.
+pub fn attribute_name(type_: u16) -> &'static str {
+    match type_ {
+        MAPPED_ADDRESS => "MAPPED-ADDRESS",
+        USERNAME => "USERNAME",
+        MESSAGE_INTEGRITY => "MESSAGE-INTEGRITY",
+        ERROR_CODE => "ERROR-CODE",
+        UNKNOWN_ATTRIBUTES => "UNKNOWN-ATTRIBUTES",
+        REALM => "REALM",
+        NONCE => "NONCE",
+        MESSAGE_INTEGRITY_SHA256 => "MESSAGE-INTEGRITY-SHA256",
+        PASSWORD_ALGORITHM => "PASSWORD-ALGORITHM",
+        USERHASH => "USERHASH",
+        XOR_MAPPED_ADDRESS => "XOR-MAPPED-ADDRESS",
+        PASSWORD_ALGORITHMS => "PASSWORD-ALGORITHMS",
+        SOFTWARE => "SOFTWARE",
+        ALTERNATE_SERVER => "ALTERNATE-SERVER",
+        ALTERNATE_DOMAIN => "ALTERNATE-DOMAIN",
+        FINGERPRINT => "FINGERPRINT",
+        RESPONSE_ORIGIN => "RESPONSE-ORIGIN",
+        _ => "UNKNOWN",
+    }
+}
+
+fn address_length(address: SocketAddr) -> u16 {
+    match address.ip() {
+        IpAddr::V4(_ip) => 8,
+        IpAddr::V6(_ip) => 20,
+    }
+}
+
+/// The length of an attribute with a value of `length` bytes, padded to a multiple of four.
+fn padded_length(length: u16) -> usize {
+    4 + usize::from(length).div_ceil(4) * 4
+}
+
+/// Writes the attribute header and zeroes the padding, returning the space for the value.
+fn encode_header(buffer: &mut [u8], type_: u16, length: u16) -> &mut [u8] {
+    BigEndian::write_u16(&mut buffer[0..2], type_);
+    BigEndian::write_u16(&mut buffer[2..4], length);
+    let end = 4 + usize::from(length);
+    for padding in &mut buffer[end..] {
+        *padding = 0;
+    }
+    &mut buffer[4..end]
+}
+
+fn encode_address(buffer: &mut [u8], type_: u16, length: u16, address: SocketAddr) {
+    let value = encode_header(buffer, type_, length);
+    BigEndian::write_u16(&mut value[2..4], address.port());
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            BigEndian::write_u16(&mut value[0..2], 0x01);
+            value[4..].copy_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            BigEndian::write_u16(&mut value[0..2], 0x02);
+            value[4..].copy_from_slice(&ip.octets());
         }
     }
 }
@@ -252,4 +709,79 @@ mod tests {
             "225.18.166.67:41287".parse().unwrap()
         );
     }
+
+    #[test]
+    fn test_error_code_and_unknown_attributes_encoding() {
+        let encoded = ErrorCode::new(420, "Unknown Attribute".to_owned()).serialize();
+        assert_eq!(&encoded[4..8], &[0, 0, 4, 20]);
+        assert_eq!(encoded.len() % 4, 0);
+
+        let encoded = UnknownAttributes::new(vec![0x0003, 0x0026, 0x7000]).serialize();
+        assert_eq!(BigEndian::read_u16(&encoded[2..4]), 6);
+        assert_eq!(&encoded[4..], &[0, 3, 0, 0x26, 0x70, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_software_encoding() {
+        let encoded = Software::new("stun/1.0".to_owned()).serialize();
+        assert_eq!(&encoded[..4], &[0x80, 0x22, 0, 8]);
+        assert_eq!(&encoded[4..], b"stun/1.0");
+        assert_eq!(Software::new("abcde".to_owned()).serialize().len(), 12);
+    }
+
+    /// A vendor attribute as users of the crate define them.
+    #[derive(Debug, PartialEq)]
+    struct TenantId(u32);
+
+    impl CustomAttribute for TenantId {
+        const TYPE: u16 = 0xC001;
+        const NAME: &'static str = "TENANT-ID";
+
+        fn decode(value: &[u8]) -> Option<Self> {
+            (value.len() == 4).then(|| TenantId(BigEndian::read_u32(value)))
+        }
+
+        fn value_length(&self) -> u16 {
+            4
+        }
+
+        fn encode_value(&self, value: &mut [u8]) {
+            BigEndian::write_u32(value, self.0);
+        }
+    }
+
+    #[test]
+    fn test_custom_attributes() {
+        let encoded = Custom(TenantId(42)).serialize();
+        assert_eq!(encoded, [0xC0, 0x01, 0, 4, 0, 0, 0, 42]);
+        assert_eq!(TenantId::decode(&encoded[4..]), Some(TenantId(42)));
+
+        let mut registry = AttributeRegistry::new();
+        assert_eq!(registry.name(0xC001), "UNKNOWN");
+        assert_eq!(registry.describe(0xC001, &encoded[4..]), None);
+        registry.register::<TenantId>();
+        assert_eq!(registry.name(0xC001), "TENANT-ID");
+        assert_eq!(registry.name(USERNAME), "USERNAME");
+        assert_eq!(
+            registry.describe(0xC001, &encoded[4..]).as_deref(),
+            Some("TenantId(42)")
+        );
+        assert_eq!(registry.describe(0xC001, &[1]), None);
+        assert_eq!(attribute_name(0xC001), "UNKNOWN");
+    }
+
+    #[test]
+    fn test_xor_decoding_round_trip() {
+        for address in &[
+            "192.0.2.1:32853",
+            "[2001:db8:1234:5678:11:2233:4455:6677]:32853",
+        ] {
+            let address: SocketAddr = address.parse().unwrap();
+            let encoded = XorMappedAddress::new(address, &TRANSACTION_ID).serialize();
+            assert_eq!(
+                decode_xor_address(&encoded[4..], &TRANSACTION_ID),
+                Some(address)
+            );
+        }
+    }
 }
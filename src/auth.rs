@@ -0,0 +1,721 @@
+//! Authentication with the short-term and long-term credential mechanisms (RFC 8489
+//! section 9).
+//!
+//! Long-term credentials use the RFC 8489 security features: nonces start with the
+//! `obMatJos2` cookie and the features the server offers, PASSWORD-ALGORITHMS lets clients
+//! derive a SHA-256 key instead of an MD5 one, and USERHASH hides the username. RFC 5389
+//! clients send none of these and are checked with MD5 keys as before. Usernames, realms and
+//! passwords are used as given, without OpaqueString normalization.
+use crate::attributes::{
+    Attribute, PasswordAlgorithms, Text, MESSAGE_INTEGRITY, MESSAGE_INTEGRITY_SHA256, NONCE,
+    PASSWORD_ALGORITHM, REALM, USERHASH, USERNAME,
+};
+use crate::config::{AuthConfig, AuthMode, PasswordAlgorithm};
+use crate::errors::ErrorCodeEnum;
+use crate::message::{StunMessageBuilder, StunMessageRef, HEADER_LENGTH};
+use crate::ratelimit::canonical;
+use byteorder::{BigEndian, ByteOrder};
+use md5::{Digest, Md5};
+use ring::{digest, hmac};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// Starts every nonce of a server that supports the RFC 8489 security features.
+pub const NONCE_COOKIE: &str = "obMatJos2";
+/// Security feature bits carried in the nonce (RFC 8489 section 18.1), bit 0 first.
+const FEATURE_PASSWORD_ALGORITHMS: u32 = 1 << 23;
+const FEATURE_USERNAME_ANONYMITY: u32 = 1 << 22;
+/// Bytes of the nonce MAC that are kept, hex encoded after the timestamp.
+const NONCE_MAC_LENGTH: usize = 16;
+
+impl PasswordAlgorithm {
+    /// The number registered for the algorithm.
+    pub fn number(self) -> u16 {
+        match self {
+            PasswordAlgorithm::Md5 => 0x0001,
+            PasswordAlgorithm::Sha256 => 0x0002,
+        }
+    }
+
+    fn from_number(number: u16) -> Option<PasswordAlgorithm> {
+        match number {
+            0x0001 => Some(PasswordAlgorithm::Md5),
+            0x0002 => Some(PasswordAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// The long-term key of `username` (RFC 8489 section 9.2.2).
+    pub fn key(self, username: &str, realm: &str, password: &str) -> Vec<u8> {
+        let input = format!("{}:{}:{}", username, realm, password);
+        match self {
+            // MD5 is kept for RFC 5389 clients.
+            PasswordAlgorithm::Md5 => Md5::digest(input.as_bytes()).to_vec(),
+            PasswordAlgorithm::Sha256 => digest::digest(&digest::SHA256, input.as_bytes())
+                .as_ref()
+                .to_vec(),
+        }
+    }
+}
+
+/// The USERHASH of `username` in `realm`.
+pub fn userhash(username: &str, realm: &str) -> Vec<u8> {
+    let input = format!("{}:{}", username, realm);
+    digest::digest(&digest::SHA256, input.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+/// Which attribute carries the HMAC of a message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Integrity {
+    /// MESSAGE-INTEGRITY, HMAC-SHA1.
+    Sha1,
+    /// MESSAGE-INTEGRITY-SHA256, HMAC-SHA256 truncated to `length` bytes.
+    Sha256 { length: usize },
+}
+
+impl Integrity {
+    pub(crate) fn attribute_type(self) -> u16 {
+        match self {
+            Integrity::Sha1 => MESSAGE_INTEGRITY,
+            Integrity::Sha256 { .. } => MESSAGE_INTEGRITY_SHA256,
+        }
+    }
+
+    pub(crate) fn length(self) -> usize {
+        match self {
+            Integrity::Sha1 => 20,
+            Integrity::Sha256 { length } => length,
+        }
+    }
+
+    /// The HMAC of `message`, whose header length must already count the attribute.
+    pub fn hmac(self, key: &[u8], message: &[u8]) -> Vec<u8> {
+        let algorithm = match self {
+            Integrity::Sha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            Integrity::Sha256 { .. } => hmac::HMAC_SHA256,
+        };
+        let tag = hmac::sign(&hmac::Key::new(algorithm, key), message);
+        tag.as_ref()[..self.length()].to_vec()
+    }
+}
+
+/// The key of an authenticated request, which protects the response.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    key: Vec<u8>,
+    integrity: Integrity,
+}
+
+impl Credentials {
+    /// Adds the integrity attribute the request used, computed with the same key.
+    pub fn protect(&self, message: StunMessageBuilder) -> StunMessageBuilder {
+        message.integrity_with(self.integrity, &self.key)
+    }
+}
+
+/// Why a request was not authenticated: the error to send and the attributes that go with it.
+pub struct Rejection {
+    pub code: ErrorCodeEnum,
+    pub attributes: Vec<Box<dyn Attribute>>,
+}
+
+impl Rejection {
+    fn bad_request() -> Rejection {
+        Rejection {
+            code: ErrorCodeEnum::BadRequest,
+            attributes: Vec::new(),
+        }
+    }
+}
+
+/// Nonces handed out in challenges, each valid for one source address for a while.
+///
+/// Nonces are not stored: each carries the time it was issued and a MAC over that time, the
+/// features and the source address, so any server sharing a secret can check it.
+#[derive(Debug)]
+struct Nonces {
+    features: u32,
+    lifetime: Duration,
+    /// The first signs new nonces, all are accepted. Replaced by `reload`.
+    keys: RwLock<Vec<hmac::Key>>,
+}
+
+impl Nonces {
+    fn new(features: u32, lifetime: Duration, secrets: &[String]) -> Nonces {
+        let mut keys = nonce_keys(secrets);
+        if keys.is_empty() {
+            let mut secret = [0_u8; 32];
+            getrandom::getrandom(&mut secret).expect("no random numbers available");
+            keys.push(hmac::Key::new(hmac::HMAC_SHA256, &secret));
+        }
+        Nonces {
+            features,
+            lifetime,
+            keys: RwLock::new(keys),
+        }
+    }
+
+    /// Signs new nonces with the first of `secrets` and accepts those signed with any of
+    /// them. Without secrets the current keys are kept, so outstanding nonces stay valid.
+    fn reload(&self, secrets: &[String]) {
+        let keys = nonce_keys(secrets);
+        if !keys.is_empty() {
+            *self.keys.write().unwrap() = keys;
+        }
+    }
+
+    fn issue(&self, source: IpAddr) -> String {
+        self.issue_at(source, unix_time())
+    }
+
+    fn issue_at(&self, source: IpAddr, now: u64) -> String {
+        let prefix = format!(
+            "{}{}{:016x}",
+            NONCE_COOKIE,
+            encode_features(self.features),
+            now
+        );
+        let mac = nonce_mac(&self.keys.read().unwrap()[0], &prefix, source);
+        prefix + &mac
+    }
+
+    /// Whether `nonce` was issued to `source` and has not expired.
+    fn is_valid(&self, nonce: &str, source: IpAddr) -> bool {
+        self.is_valid_at(nonce, source, unix_time())
+    }
+
+    fn is_valid_at(&self, nonce: &str, source: IpAddr, now: u64) -> bool {
+        let header_length = NONCE_COOKIE.len() + 4;
+        let mac_start = header_length + 16;
+        if nonce.len() != mac_start + 2 * NONCE_MAC_LENGTH
+            || !nonce.is_ascii()
+            || nonce[..header_length]
+                != format!("{}{}", NONCE_COOKIE, encode_features(self.features))
+        {
+            return false;
+        }
+        let issued = match u64::from_str_radix(&nonce[header_length..mac_start], 16) {
+            Ok(issued) => issued,
+            Err(_) => return false,
+        };
+        // Nonces from the future are only tolerated within the lifetime, for clock skew.
+        let age = Duration::from_secs(now.saturating_sub(issued).max(issued.saturating_sub(now)));
+        if age >= self.lifetime {
+            return false;
+        }
+        let (prefix, mac) = nonce.split_at(mac_start);
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .any(|key| constant_time_eq(nonce_mac(key, prefix, source).as_bytes(), mac.as_bytes()))
+    }
+}
+
+fn nonce_keys(secrets: &[String]) -> Vec<hmac::Key> {
+    secrets
+        .iter()
+        .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+        .collect()
+}
+
+/// The hex MAC of a nonce, binding `prefix` to the canonical `source` address.
+fn nonce_mac(key: &hmac::Key, prefix: &str, source: IpAddr) -> String {
+    let address = match canonical(source) {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    };
+    let mut context = hmac::Context::with_key(key);
+    context.update(prefix.as_bytes());
+    context.update(&address);
+    crate::logging::to_hex(&context.sign().as_ref()[..NONCE_MAC_LENGTH])
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// The security feature bits as the four base64 characters that follow the cookie.
+fn encode_features(features: u32) -> String {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    (0..4)
+        .rev()
+        .map(|index| BASE64[((features >> (index * 6)) & 0x3F) as usize] as char)
+        .collect()
+}
+
+/// Checks requests against the configured users.
+#[derive(Debug)]
+pub struct Authenticator {
+    mode: AuthMode,
+    realm: String,
+    users: HashMap<String, String>,
+    /// Usernames by USERHASH.
+    userhashes: HashMap<Vec<u8>, String>,
+    password_algorithms: Vec<PasswordAlgorithm>,
+    nonces: Nonces,
+}
+
+impl Authenticator {
+    /// `None` when authentication is off.
+    pub fn new(config: &AuthConfig) -> Option<Authenticator> {
+        if config.mode == AuthMode::None {
+            return None;
+        }
+        if config.mode == AuthMode::LongTerm && config.nonce_secrets.is_empty() {
+            // Replicas behind one address would reject each other's nonces with 438.
+            warn!("auth.nonce_secrets is unset, nonces are only valid on this process");
+        }
+        let realm = config.realm.clone().unwrap_or_default();
+        Some(Authenticator {
+            mode: config.mode,
+            userhashes: config
+                .users
+                .keys()
+                .map(|username| (userhash(username, &realm), username.clone()))
+                .collect(),
+            realm,
+            users: config.users.clone().into_iter().collect(),
+            password_algorithms: config.password_algorithms.clone(),
+            nonces: Nonces::new(
+                FEATURE_PASSWORD_ALGORITHMS | FEATURE_USERNAME_ANONYMITY,
+                Duration::from_secs(config.nonce_lifetime),
+                &config.nonce_secrets,
+            ),
+        })
+    }
+
+    /// Replaces the nonce secrets with those in `config`, e.g. when the configuration is
+    /// reloaded. Nonces signed with a secret still listed stay valid; an empty list keeps
+    /// the current secrets. Users and other settings only change on restart.
+    pub fn reload_nonce_secrets(&self, config: &AuthConfig) {
+        self.nonces.reload(&config.nonce_secrets);
+    }
+
+    /// Authenticates `request`, received from `source`.
+    pub fn authenticate(
+        &self,
+        request: &StunMessageRef,
+        source: IpAddr,
+    ) -> Result<Credentials, Rejection> {
+        let message = request.as_bytes();
+        let integrity = match integrity_attribute(message) {
+            Some(integrity) => integrity,
+            // The long-term mechanism answers with a challenge, the short-term one has none.
+            None if self.mode == AuthMode::LongTerm => {
+                return Err(self.challenge(ErrorCodeEnum::Unauthorized, source))
+            }
+            None => return Err(Rejection::bad_request()),
+        };
+        let (username, key) = match self.mode {
+            AuthMode::LongTerm => self.long_term_key(request, source)?,
+            _ => {
+                let username = text(request, USERNAME).ok_or_else(Rejection::bad_request)?;
+                match self.users.get(&username) {
+                    Some(password) => (username, password.as_bytes().to_vec()),
+                    None => return Err(self.unauthorized(source)),
+                }
+            }
+        };
+
+        let (integrity, offset, hmac) = integrity;
+        let mut signed = message[..offset].to_vec();
+        let length = offset - HEADER_LENGTH + 4 + hmac.len();
+        BigEndian::write_u16(&mut signed[2..4], length as u16);
+        let expected = integrity.hmac(&key, &signed);
+        if !constant_time_eq(&expected, hmac) {
+            debug!(%username, "request failed the integrity check");
+            return Err(self.unauthorized(source));
+        }
+        Ok(Credentials {
+            username,
+            key,
+            integrity,
+        })
+    }
+
+    /// The username and key of a long-term credential request.
+    fn long_term_key(
+        &self,
+        request: &StunMessageRef,
+        source: IpAddr,
+    ) -> Result<(String, Vec<u8>), Rejection> {
+        let realm = text(request, REALM).ok_or_else(Rejection::bad_request)?;
+        let nonce = text(request, NONCE).ok_or_else(Rejection::bad_request)?;
+        if request.attribute(USERNAME).is_none() && request.attribute(USERHASH).is_none() {
+            return Err(Rejection::bad_request());
+        }
+        if !self.nonces.is_valid(&nonce, source) {
+            return Err(self.challenge(ErrorCodeEnum::StaleNonce, source));
+        }
+        let algorithm = self.password_algorithm(request)?;
+        let username = match request.attribute(USERHASH) {
+            Some(userhash) => self.userhashes.get(userhash.value).cloned(),
+            None => text(request, USERNAME),
+        };
+        let password = username
+            .as_ref()
+            .filter(|_| realm == self.realm)
+            .and_then(|username| self.users.get(username));
+        match (username, password) {
+            (Some(username), Some(password)) => {
+                let key = algorithm.key(&username, &realm, password);
+                Ok((username, key))
+            }
+            _ => Err(self.unauthorized(source)),
+        }
+    }
+
+    /// The algorithm the client chose. Every nonce announces PASSWORD-ALGORITHMS, so the
+    /// client must echo the list it got unchanged; a list altered on the way, to bid the
+    /// client down to MD5, is refused (RFC 8489 section 9.2.4).
+    fn password_algorithm(&self, request: &StunMessageRef) -> Result<PasswordAlgorithm, Rejection> {
+        let offered = request.attribute(crate::attributes::PASSWORD_ALGORITHMS);
+        let chosen = request.attribute(PASSWORD_ALGORITHM);
+        let algorithm = match (offered, chosen) {
+            // An RFC 5389 client.
+            (None, None) => PasswordAlgorithm::Md5,
+            (Some(offered), Some(chosen)) if offered.value == self.encoded_algorithms() => {
+                if chosen.value.len() < 4 {
+                    return Err(Rejection::bad_request());
+                }
+                PasswordAlgorithm::from_number(BigEndian::read_u16(&chosen.value[0..2]))
+                    .ok_or_else(Rejection::bad_request)?
+            }
+            _ => {
+                debug!("PASSWORD-ALGORITHMS does not match the one offered");
+                return Err(Rejection::bad_request());
+            }
+        };
+        if !self.password_algorithms.contains(&algorithm) {
+            return Err(Rejection::bad_request());
+        }
+        Ok(algorithm)
+    }
+
+    fn encoded_algorithms(&self) -> Vec<u8> {
+        PasswordAlgorithms::new(self.algorithm_numbers()).serialize()[4..].to_vec()
+    }
+
+    fn algorithm_numbers(&self) -> Vec<u16> {
+        self.password_algorithms
+            .iter()
+            .map(|algorithm| algorithm.number())
+            .collect()
+    }
+
+    fn unauthorized(&self, source: IpAddr) -> Rejection {
+        match self.mode {
+            AuthMode::LongTerm => self.challenge(ErrorCodeEnum::Unauthorized, source),
+            _ => Rejection {
+                code: ErrorCodeEnum::Unauthorized,
+                attributes: Vec::new(),
+            },
+        }
+    }
+
+    /// An error carrying REALM, a fresh NONCE and PASSWORD-ALGORITHMS to retry with.
+    fn challenge(&self, code: ErrorCodeEnum, source: IpAddr) -> Rejection {
+        Rejection {
+            code,
+            attributes: vec![
+                Box::new(Text::realm(self.realm.clone())),
+                Box::new(Text::nonce(self.nonces.issue(source))),
+                Box::new(PasswordAlgorithms::new(self.algorithm_numbers())),
+            ],
+        }
+    }
+}
+
+/// The integrity attribute that protects `message`, its offset and its HMAC.
+/// MESSAGE-INTEGRITY-SHA256 is used when both are present. Lengths RFC 8489 does not allow
+/// leave the request unprotected.
+fn integrity_attribute(message: &[u8]) -> Option<(Integrity, usize, &[u8])> {
+    let mut found = None;
+    let mut offset = HEADER_LENGTH;
+    while offset + 4 <= message.len() {
+        let type_ = BigEndian::read_u16(&message[offset..]);
+        let length = BigEndian::read_u16(&message[offset + 2..]) as usize;
+        let value = message.get(offset + 4..offset + 4 + length)?;
+        match type_ {
+            MESSAGE_INTEGRITY if length == 20 => found = Some((Integrity::Sha1, offset, value)),
+            MESSAGE_INTEGRITY_SHA256 if (16..=32).contains(&length) && length.is_multiple_of(4) => {
+                return Some((Integrity::Sha256 { length }, offset, value))
+            }
+            _ if found.is_some() => break,
+            _ => {}
+        }
+        offset += 4 + ((length + 3) & !3);
+    }
+    found
+}
+
+fn text(request: &StunMessageRef, type_: u16) -> Option<String> {
+    String::from_utf8(request.attribute(type_)?.value.to_vec()).ok()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attributes::PASSWORD_ALGORITHMS;
+    use crate::logging::to_hex;
+    use crate::message::{Class, Method, StunHeader, StunMessage};
+
+    fn authenticator(mode: AuthMode) -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            mode,
+            realm: Some("example.org".to_owned()),
+            users: vec![("alice".to_owned(), "secret".to_owned())]
+                .into_iter()
+                .collect(),
+            ..AuthConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn append(message: &mut Vec<u8>, type_: u16, value: &[u8]) {
+        let mut attribute = vec![0_u8; 4];
+        BigEndian::write_u16(&mut attribute[0..2], type_);
+        BigEndian::write_u16(&mut attribute[2..4], value.len() as u16);
+        attribute.extend_from_slice(value);
+        attribute.resize((attribute.len() + 3) & !3, 0);
+        message.extend(attribute);
+    }
+
+    /// A Binding request with `attributes`, protected with `key` when given.
+    fn request(attributes: &[(u16, &[u8])], integrity: Option<(Integrity, &[u8])>) -> Vec<u8> {
+        let mut message = StunHeader::new(Method::BINDING, Class::Request, 0, [7; 12]).serialize();
+        for (type_, value) in attributes {
+            append(&mut message, *type_, value);
+        }
+        if let Some((integrity, key)) = integrity {
+            let length = message.len() - HEADER_LENGTH + 4 + integrity.length();
+            BigEndian::write_u16(&mut message[2..4], length as u16);
+            let hmac = integrity.hmac(key, &message);
+            append(&mut message, integrity.attribute_type(), &hmac);
+        }
+        let length = (message.len() - HEADER_LENGTH) as u16;
+        BigEndian::write_u16(&mut message[2..4], length);
+        message
+    }
+
+    fn authenticate(
+        authenticator: &Authenticator,
+        message: &[u8],
+    ) -> Result<Credentials, ErrorCodeEnum> {
+        let request = StunMessageRef::parse(message).unwrap();
+        authenticator
+            .authenticate(&request, "192.0.2.1".parse().unwrap())
+            .map_err(|rejection| rejection.code)
+    }
+
+    /// The NONCE of the challenge a request without credentials gets.
+    fn nonce(authenticator: &Authenticator) -> Vec<u8> {
+        let request = request(&[], None);
+        let rejection = authenticator
+            .authenticate(
+                &StunMessageRef::parse(&request).unwrap(),
+                "192.0.2.1".parse().unwrap(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(rejection.code, ErrorCodeEnum::Unauthorized);
+        let nonce = rejection.attributes[1].serialize();
+        assert_eq!(BigEndian::read_u16(&nonce[0..2]), NONCE);
+        let length = BigEndian::read_u16(&nonce[2..4]) as usize;
+        nonce[4..4 + length].to_vec()
+    }
+
+    #[test]
+    fn test_rfc8489_client_with_userhash_and_sha256() {
+        let authenticator = authenticator(AuthMode::LongTerm);
+        let nonce = nonce(&authenticator);
+        assert!(nonce.starts_with(b"obMatJos2wAAA"));
+        let key = PasswordAlgorithm::Sha256.key("alice", "example.org", "secret");
+        let userhash = userhash("alice", "example.org");
+        let offered = authenticator.encoded_algorithms();
+        assert_eq!(offered, [0, 2, 0, 0, 0, 1, 0, 0]);
+        let attributes: &[(u16, &[u8])] = &[
+            (USERHASH, &userhash),
+            (REALM, b"example.org"),
+            (NONCE, &nonce),
+            (PASSWORD_ALGORITHMS, &offered),
+            (PASSWORD_ALGORITHM, &[0, 2, 0, 0]),
+        ];
+
+        for length in &[32, 16] {
+            let integrity = Integrity::Sha256 { length: *length };
+            let message = request(attributes, Some((integrity, &key)));
+            let credentials = authenticate(&authenticator, &message).unwrap();
+            assert_eq!(credentials.username, "alice");
+
+            // The response is protected the same way.
+            let response = credentials
+                .protect(StunMessage::builder(
+                    Method::BINDING,
+                    Class::SuccessResponse,
+                    [7; 12],
+                ))
+                .build()
+                .into_bytes();
+            let (used, offset, hmac) = integrity_attribute(&response).unwrap();
+            assert_eq!(used, integrity);
+            assert_eq!(integrity.hmac(&key, &response[..offset]), hmac);
+        }
+
+        // Truncated below 16 bytes.
+        let message = request(attributes, Some((Integrity::Sha256 { length: 12 }, &key)));
+        assert_eq!(
+            authenticate(&authenticator, &message).err(),
+            Some(ErrorCodeEnum::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn test_rfc5389_client_and_bid_down() {
+        let authenticator = authenticator(AuthMode::LongTerm);
+        let nonce = nonce(&authenticator);
+        let md5_key = PasswordAlgorithm::Md5.key("alice", "example.org", "secret");
+        let attributes: &[(u16, &[u8])] = &[
+            (USERNAME, b"alice"),
+            (REALM, b"example.org"),
+            (NONCE, &nonce),
+        ];
+        let message = request(attributes, Some((Integrity::Sha1, &md5_key)));
+        assert!(authenticate(&authenticator, &message).is_ok());
+
+        // SHA-256 was removed from PASSWORD-ALGORITHMS on the way to the client.
+        let mut downgraded = attributes.to_vec();
+        downgraded.push((PASSWORD_ALGORITHMS, &[0, 1, 0, 0]));
+        downgraded.push((PASSWORD_ALGORITHM, &[0, 1, 0, 0]));
+        let message = request(&downgraded, Some((Integrity::Sha1, &md5_key)));
+        assert_eq!(
+            authenticate(&authenticator, &message).err(),
+            Some(ErrorCodeEnum::BadRequest)
+        );
+
+        let wrong_key = PasswordAlgorithm::Md5.key("alice", "example.org", "guess");
+        let message = request(attributes, Some((Integrity::Sha1, &wrong_key)));
+        assert_eq!(
+            authenticate(&authenticator, &message).err(),
+            Some(ErrorCodeEnum::Unauthorized)
+        );
+
+        let stale: &[(u16, &[u8])] = &[
+            (USERNAME, b"alice"),
+            (REALM, b"example.org"),
+            (NONCE, b"obMatJos2wAAA0000"),
+        ];
+        let message = request(stale, Some((Integrity::Sha1, &md5_key)));
+        assert_eq!(
+            authenticate(&authenticator, &message).err(),
+            Some(ErrorCodeEnum::StaleNonce)
+        );
+
+        let message = request(&attributes[..1], Some((Integrity::Sha1, &md5_key)));
+        assert_eq!(
+            authenticate(&authenticator, &message).err(),
+            Some(ErrorCodeEnum::BadRequest)
+        );
+    }
+
+    #[test]
+    fn test_short_term_credentials() {
+        let authenticator = authenticator(AuthMode::ShortTerm);
+        let message = request(&[(USERNAME, b"alice")], Some((Integrity::Sha1, b"secret")));
+        assert!(authenticate(&authenticator, &message).is_ok());
+        let message = request(&[(USERNAME, b"alice")], None);
+        assert_eq!(
+            authenticate(&authenticator, &message).err(),
+            Some(ErrorCodeEnum::BadRequest)
+        );
+        let message = request(&[(USERNAME, b"alice")], Some((Integrity::Sha1, b"guess")));
+        assert_eq!(
+            authenticate(&authenticator, &message).err(),
+            Some(ErrorCodeEnum::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn test_md5_key() {
+        assert_eq!(
+            to_hex(&PasswordAlgorithm::Md5.key("alice", "example.org", "secret")),
+            "543e1aec5d3614f03141652d6ada51b2"
+        );
+    }
+
+    #[test]
+    fn test_nonce_cookie_and_features() {
+        assert_eq!(
+            encode_features(FEATURE_PASSWORD_ALGORITHMS | FEATURE_USERNAME_ANONYMITY),
+            "wAAA"
+        );
+        let lifetime = Duration::from_secs(600);
+        let secrets = ["old".to_owned()];
+        let nonces = Nonces::new(FEATURE_PASSWORD_ALGORITHMS, lifetime, &secrets);
+        let source = "192.0.2.1".parse().unwrap();
+        let nonce = nonces.issue(source);
+        assert!(nonce.starts_with("obMatJos2gAAA"));
+        assert!(nonce.len() < 128);
+        assert!(nonces.is_valid(&nonce, source));
+        assert!(nonces.is_valid(&nonce, "::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!nonces.is_valid(&nonce, "192.0.2.2".parse().unwrap()));
+        let forged = format!("{}{}", &nonce[..nonce.len() - 1], "x");
+        assert!(!nonces.is_valid(&forged, source));
+        assert!(!nonces.is_valid("obMatJos2gAAAforged", source));
+    }
+
+    #[test]
+    fn test_nonces_expire_and_rotate() {
+        let lifetime = Duration::from_secs(600);
+        let source = "2001:db8::1".parse().unwrap();
+        let old = Nonces::new(0, lifetime, &["old".to_owned()]);
+        let nonce = old.issue_at(source, 1_000_000);
+        assert!(old.is_valid_at(&nonce, source, 1_000_599));
+        assert!(!old.is_valid_at(&nonce, source, 1_000_600));
+        assert!(!old.is_valid_at(&nonce, source, 998_000));
+
+        // During the overlap a replica signing with the new secret still accepts old nonces.
+        let rotating = Nonces::new(0, lifetime, &["new".to_owned(), "old".to_owned()]);
+        assert!(rotating.is_valid_at(&nonce, source, 1_000_001));
+        let rotated = Nonces::new(0, lifetime, &["new".to_owned()]);
+        assert!(!rotated.is_valid_at(&nonce, source, 1_000_001));
+        let nonce = rotating.issue_at(source, 1_000_000);
+        assert!(rotated.is_valid_at(&nonce, source, 1_000_001));
+        assert!(!old.is_valid_at(&nonce, source, 1_000_001));
+    }
+
+    #[test]
+    fn test_reloaded_secrets_overlap() {
+        let lifetime = Duration::from_secs(600);
+        let source = "192.0.2.1".parse().unwrap();
+        let nonces = Nonces::new(0, lifetime, &["old".to_owned()]);
+        let old_nonce = nonces.issue_at(source, 1_000_000);
+
+        nonces.reload(&["new".to_owned(), "old".to_owned()]);
+        assert!(nonces.is_valid_at(&old_nonce, source, 1_000_001));
+        // New nonces are signed with the new secret, so replicas that have it accept them.
+        let new_nonce = nonces.issue_at(source, 1_000_001);
+        assert!(Nonces::new(0, lifetime, &["new".to_owned()])
+            .is_valid_at(&new_nonce, source, 1_000_002));
+
+        nonces.reload(&[]);
+        assert!(nonces.is_valid_at(&old_nonce, source, 1_000_002));
+        nonces.reload(&["new".to_owned()]);
+        assert!(!nonces.is_valid_at(&old_nonce, source, 1_000_002));
+        assert!(nonces.is_valid_at(&new_nonce, source, 1_000_002));
+    }
+}
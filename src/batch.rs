@@ -0,0 +1,249 @@
+//! Batched UDP I/O for Linux.
+//!
+//! Reads and writes up to `BATCH_SIZE` datagrams per syscall with `recvmmsg(2)` and
+//! `sendmmsg(2)`. The receive buffers are allocated once per socket and reused for every
+//! batch, so the hot loop does not allocate for incoming packets.
+use crate::shutdown::{Shutdown, ShutdownSummary};
+use crate::stunserver::{handle_udp_connection, is_socket_closed, ListenerContext};
+use crate::udp::{
+    from_socket_addr, prepare_header, read_pktinfo, to_socket_addr, write_pktinfo, ControlBuffer,
+};
+use std::error::Error;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+pub const BATCH_SIZE: usize = 32;
+pub const DATAGRAM_SIZE: usize = 1024;
+
+pub struct BatchBuffers {
+    buffers: Box<[[u8; DATAGRAM_SIZE]; BATCH_SIZE]>,
+    addresses: [libc::sockaddr_storage; BATCH_SIZE],
+    controls: [ControlBuffer; BATCH_SIZE],
+    lengths: [usize; BATCH_SIZE],
+    locals: [Option<IpAddr>; BATCH_SIZE],
+    received: usize,
+}
+
+impl BatchBuffers {
+    pub fn new() -> Self {
+        BatchBuffers {
+            buffers: Box::new([[0_u8; DATAGRAM_SIZE]; BATCH_SIZE]),
+            // An all-zero sockaddr_storage is a valid (AF_UNSPEC) address.
+            addresses: unsafe { mem::zeroed() },
+            controls: [ControlBuffer::new(); BATCH_SIZE],
+            lengths: [0; BATCH_SIZE],
+            locals: [None; BATCH_SIZE],
+            received: 0,
+        }
+    }
+
+    /// Iterates over the datagrams read by the last call to `recv_batch`, with the peer and,
+    /// when `IP_PKTINFO` is enabled, the local address each one was sent to.
+    pub fn datagrams(&self) -> impl Iterator<Item = (&[u8], SocketAddr, Option<IpAddr>)> + '_ {
+        (0..self.received).filter_map(move |i| {
+            let address = to_socket_addr(&self.addresses[i])?;
+            Some((&self.buffers[i][..self.lengths[i]], address, self.locals[i]))
+        })
+    }
+}
+
+impl Default for BatchBuffers {
+    fn default() -> Self {
+        BatchBuffers::new()
+    }
+}
+
+/// Waits until the socket is readable and fills `buffers` with as many datagrams as are queued,
+/// up to `BATCH_SIZE`. Returns the number of datagrams received.
+pub async fn recv_batch(socket: &UdpSocket, buffers: &mut BatchBuffers) -> io::Result<usize> {
+    let fd = socket.as_raw_fd();
+    let received = socket
+        .async_io(Interest::READABLE, || recvmmsg(fd, buffers))
+        .await?;
+    buffers.received = received;
+    Ok(received)
+}
+
+/// Sends every `(payload, destination, source)` triple, issuing as few `sendmmsg` calls as
+/// possible. A datagram the kernel refuses is logged and skipped; only an error meaning the
+/// socket is gone is returned. Returns how many datagrams were sent.
+pub async fn send_batch(
+    socket: &UdpSocket,
+    datagrams: &[(Vec<u8>, SocketAddr, Option<IpAddr>)],
+) -> io::Result<usize> {
+    let fd = socket.as_raw_fd();
+    let mut next = 0;
+    let mut delivered = 0;
+    while next < datagrams.len() {
+        let end = datagrams.len().min(next + BATCH_SIZE);
+        match socket
+            .async_io(Interest::WRITABLE, || sendmmsg(fd, &datagrams[next..end]))
+            .await
+        {
+            Ok(sent) => {
+                next += sent;
+                delivered += sent;
+            }
+            Err(e) if is_socket_closed(&e) => return Err(e),
+            // sendmmsg reports an error only when the first datagram could not be sent.
+            Err(e) => {
+                warn!(peer = %datagrams[next].1, error = %e, "UDP send failed");
+                next += 1;
+            }
+        }
+    }
+    Ok(delivered)
+}
+
+/// Serves STUN requests on `socket` using batched reads and writes.
+pub async fn udp_loop(
+    socket: &UdpSocket,
+    context: &ListenerContext,
+    shutdown: &Shutdown,
+) -> Result<ShutdownSummary, Box<dyn Error>> {
+    let mut buffers = BatchBuffers::new();
+    let mut responses: Vec<(Vec<u8>, SocketAddr, Option<IpAddr>)> = Vec::with_capacity(BATCH_SIZE);
+    // Buffers of responses already sent, encoded into again.
+    let mut spare: Vec<Vec<u8>> = Vec::with_capacity(BATCH_SIZE);
+    let local_address = socket.local_addr()?;
+    let mut summary = ShutdownSummary::default();
+    loop {
+        let received = tokio::select! {
+            _ = shutdown.triggered() => return Ok(summary),
+            received = recv_batch(socket, &mut buffers) => received,
+        };
+        match received {
+            Err(e) if is_socket_closed(&e) => return Err(e.into()),
+            Err(e) => {
+                warn!(error = %e, "UDP receive failed");
+                continue;
+            }
+            Ok(_) => {}
+        }
+
+        spare.extend(responses.drain(..).map(|(response, _, _)| response));
+        for (message, address, local) in buffers.datagrams() {
+            if !context.admit(address.ip()) {
+                continue;
+            }
+            let origin = SocketAddr::new(
+                local.unwrap_or_else(|| local_address.ip()),
+                local_address.port(),
+            );
+            let buffer = spare.pop().unwrap_or_default();
+            if let Some(response) =
+                handle_udp_connection(buffer, message, address, origin, context, shutdown).await
+            {
+                responses.push((response, address, local));
+            }
+        }
+        summary.udp_requests += send_batch(socket, &responses).await? as u64;
+    }
+}
+
+fn recvmmsg(fd: RawFd, buffers: &mut BatchBuffers) -> io::Result<usize> {
+    let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+    for i in 0..BATCH_SIZE {
+        iovecs[i].iov_base = buffers.buffers[i].as_mut_ptr() as *mut libc::c_void;
+        iovecs[i].iov_len = DATAGRAM_SIZE;
+        prepare_header(
+            &mut headers[i].msg_hdr,
+            &mut buffers.addresses[i],
+            &mut iovecs[i],
+            &mut buffers.controls[i],
+        );
+    }
+
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            BATCH_SIZE as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let received = received as usize;
+    for (i, header) in headers[..received].iter().enumerate() {
+        buffers.lengths[i] = header.msg_len as usize;
+        buffers.locals[i] = read_pktinfo(&header.msg_hdr);
+    }
+    Ok(received)
+}
+
+fn sendmmsg(fd: RawFd, datagrams: &[(Vec<u8>, SocketAddr, Option<IpAddr>)]) -> io::Result<usize> {
+    let mut addresses: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut controls = [ControlBuffer::new(); BATCH_SIZE];
+    let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let count = datagrams.len().min(BATCH_SIZE);
+
+    for (i, (payload, address, local)) in datagrams[..count].iter().enumerate() {
+        iovecs[i].iov_base = payload.as_ptr() as *mut libc::c_void;
+        iovecs[i].iov_len = payload.len();
+        let header = &mut headers[i].msg_hdr;
+        prepare_header(header, &mut addresses[i], &mut iovecs[i], &mut controls[i]);
+        header.msg_namelen = from_socket_addr(*address, &mut addresses[i]);
+        write_pktinfo(header, *address, *local);
+    }
+
+    let sent = unsafe {
+        libc::sendmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            count as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recv_and_send_batch() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server.local_addr().unwrap();
+
+        for i in 0..3_u8 {
+            client.send_to(&[i; 4], server_address).await.unwrap();
+        }
+
+        let mut buffers = BatchBuffers::new();
+        let mut received = 0;
+        while received < 3 {
+            received += recv_batch(&server, &mut buffers).await.unwrap();
+        }
+
+        let echoes: Vec<(Vec<u8>, SocketAddr, Option<IpAddr>)> = buffers
+            .datagrams()
+            .map(|(payload, address, local)| (payload.to_vec(), address, local))
+            .collect();
+        // An IPv6 destination cannot be reached from an IPv4 socket; only that one is lost.
+        let mut datagrams = vec![(vec![9; 4], "[::1]:9".parse().unwrap(), None)];
+        datagrams.extend(echoes);
+        assert_eq!(send_batch(&server, &datagrams).await.unwrap(), 3);
+
+        let mut buffer = [0_u8; 16];
+        let (length, _) = client.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(length, 4);
+    }
+}
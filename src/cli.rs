@@ -0,0 +1,646 @@
+//! Command line interface of the `stun` binary.
+//!
+//! Besides the named flags and subcommands, the positional form used by existing
+//! deployments keeps working: `stun [address [port [protocol]]]` and
+//! `stun address:port[/protocol] ...`.
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use stun::access::AccessLists;
+use stun::attributes::{
+    attribute_name, decode_address, decode_xor_address, ALTERNATE_DOMAIN, ALTERNATE_SERVER,
+    MAPPED_ADDRESS, RESPONSE_ORIGIN, XOR_MAPPED_ADDRESS,
+};
+use stun::auth::Authenticator;
+use stun::client::{binding_request, binding_request_tls, ClientTransport, TlsClient};
+use stun::config::{Config, ConfigError, LogLevel, DEFAULT_CONFIG_PATH};
+use stun::logging::{self, to_hex};
+use stun::message::DecodedMessage;
+use stun::shutdown::{Shutdown, ShutdownSummary};
+use stun::stunserver::{
+    parse_listen_addresses, parse_program_arguments, ListenAddress, StunServerBuilder,
+    StunServerEnum,
+};
+use tracing::{info, warn};
+
+const DEFAULT_PORT: u16 = 3478;
+/// How often the config file is checked for changes to the access lists.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The command ran but failed, e.g. a listener could not be bound.
+const EXIT_FAILURE: u8 = 1;
+/// The command line could not be parsed.
+const EXIT_USAGE: u8 = 2;
+/// The configuration is invalid (EX_CONFIG from sysexits.h).
+const EXIT_CONFIG: u8 = 78;
+
+const SUBCOMMANDS: &[&str] = &["serve", "check-config", "client", "decode", "help"];
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "stun",
+    version,
+    about = "STUN server and client (RFC 8489)",
+    args_conflicts_with_subcommands = true,
+    after_help = "Without a subcommand the server is started, as with `serve`.\n\
+                  Exit codes: 0 success, 1 runtime failure, 2 usage error, 78 invalid configuration."
+)]
+struct Cli {
+    /// Configuration file [default: ./stun.toml if it exists]
+    #[arg(short, long, global = true, env = "STUN_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Log level: error, warn, info, debug or trace
+    #[arg(long, global = true, value_name = "LEVEL")]
+    log_level: Option<LogLevel>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the STUN server
+    Serve(ServeArgs),
+    /// Validate the configuration and print the listeners without starting the server
+    CheckConfig(ServeArgs),
+    /// Send a Binding request to a server and print the mapped address
+    Client(ClientArgs),
+    /// Decode a hex encoded STUN message
+    Decode(DecodeArgs),
+}
+
+#[derive(Debug, Default, Args)]
+struct ServeArgs {
+    /// Address to listen on, as address[:port][/protocol]; may be repeated
+    #[arg(short, long, value_name = "ADDRESS")]
+    listen: Vec<String>,
+
+    /// Protocol for --listen addresses that do not name one [default: multiplex]
+    #[arg(short, long, value_enum, requires = "listen")]
+    transport: Option<Transport>,
+}
+
+#[derive(Debug, Args)]
+struct ClientArgs {
+    /// Server to query, as host[:port]
+    server: String,
+
+    #[arg(short, long, value_enum, default_value = "udp")]
+    transport: ClientTransportArg,
+
+    /// PEM file with the CA certificates trusted over TLS [default: the system's]
+    #[arg(long, value_name = "PATH")]
+    ca_file: Option<PathBuf>,
+
+    /// Name expected in the server's TLS certificate [default: the host in SERVER]
+    #[arg(long, value_name = "NAME")]
+    server_name: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct DecodeArgs {
+    /// Message as hex; read from stdin when omitted
+    hex: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Multiplex,
+}
+
+impl From<Transport> for StunServerEnum {
+    fn from(transport: Transport) -> Self {
+        match transport {
+            Transport::Udp => StunServerEnum::UdpStunServer,
+            Transport::Tcp => StunServerEnum::TcpStunServer,
+            Transport::Tls => StunServerEnum::TlsStunServer,
+            Transport::Multiplex => StunServerEnum::MultiplexedStunServer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ClientTransportArg {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+pub async fn run(args: Vec<String>) -> ExitCode {
+    if let Some(listen_addresses) = legacy_listen_addresses(&args) {
+        return match listen_addresses {
+            Ok(listen_addresses) => serve(None, None, Some(listen_addresses)).await,
+            Err(message) => {
+                eprintln!("error: {}\n\nFor more information, try '--help'.", message);
+                ExitCode::from(EXIT_USAGE)
+            }
+        };
+    }
+
+    let cli = match Cli::try_parse_from(args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::from(EXIT_USAGE)
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+
+    match cli.command {
+        None => serve_with_args(cli.config, cli.log_level, cli.serve).await,
+        Some(Command::Serve(serve_args)) => {
+            serve_with_args(cli.config, cli.log_level, serve_args).await
+        }
+        Some(Command::CheckConfig(serve_args)) => {
+            let listen_addresses = match listen_from_args(&serve_args) {
+                Ok(listen_addresses) => listen_addresses,
+                Err(e) => return config_error(&e),
+            };
+            match load_config(cli.config, cli.log_level, listen_addresses) {
+                Ok(config) => {
+                    println!("configuration OK");
+                    for listen in &config.listen {
+                        println!("  listen {} ({:?})", listen.address, listen.server_type);
+                    }
+                    ExitCode::SUCCESS
+                }
+                Err(e) => config_error(&e),
+            }
+        }
+        Some(Command::Client(client_args)) => report(client(client_args).await),
+        Some(Command::Decode(decode_args)) => report(decode(decode_args)),
+    }
+}
+
+/// Recognises the positional forms that predate the named flags.
+fn legacy_listen_addresses(args: &[String]) -> Option<Result<Vec<ListenAddress>, String>> {
+    let first = args.get(1)?;
+    if first.starts_with('-') || SUBCOMMANDS.contains(&first.as_str()) {
+        return None;
+    }
+    if let Some(listen_addresses) = parse_listen_addresses(args) {
+        return Some(listen_addresses);
+    }
+    Some(
+        parse_program_arguments(args.to_vec()).map(|(address, server_type)| {
+            vec![ListenAddress {
+                address,
+                server_type,
+            }]
+        }),
+    )
+}
+
+fn listen_from_args(serve_args: &ServeArgs) -> Result<Option<Vec<ListenAddress>>, ConfigError> {
+    if serve_args.listen.is_empty() {
+        return Ok(None);
+    }
+    let default_type = serve_args
+        .transport
+        .map(StunServerEnum::from)
+        .unwrap_or(StunServerEnum::MultiplexedStunServer);
+    serve_args
+        .listen
+        .iter()
+        .map(|listen| parse_listen_flag(listen, default_type))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+        .map_err(|message| ConfigError {
+            key: "--listen".to_owned(),
+            message,
+        })
+}
+
+/// Parses `address[:port][/protocol]`, filling in the default port and protocol.
+fn parse_listen_flag(listen: &str, default_type: StunServerEnum) -> Result<ListenAddress, String> {
+    let (address, protocol) = match listen.split_once('/') {
+        Some((address, protocol)) => (address, Some(protocol)),
+        None => (listen, None),
+    };
+    let address = match address.parse::<SocketAddr>() {
+        Ok(address) => address,
+        Err(_) => match address.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, DEFAULT_PORT),
+            Err(_) => return Err(format!("invalid listen address {:?}", listen)),
+        },
+    };
+    let server_type = match protocol {
+        Some(protocol) => StunServerEnum::from_protocol(protocol)
+            .ok_or_else(|| format!("unknown protocol {:?} in {:?}", protocol, listen))?,
+        None => default_type,
+    };
+    Ok(ListenAddress {
+        address,
+        server_type,
+    })
+}
+
+async fn serve_with_args(
+    config_path: Option<PathBuf>,
+    log_level: Option<LogLevel>,
+    serve_args: ServeArgs,
+) -> ExitCode {
+    match listen_from_args(&serve_args) {
+        Ok(listen_addresses) => serve(config_path, log_level, listen_addresses).await,
+        Err(e) => config_error(&e),
+    }
+}
+
+async fn serve(
+    config_path: Option<PathBuf>,
+    log_level: Option<LogLevel>,
+    listen_addresses: Option<Vec<ListenAddress>>,
+) -> ExitCode {
+    let config_path = config_path.or_else(|| std::env::var_os("STUN_CONFIG").map(PathBuf::from));
+    let config = match load_config(config_path.clone(), log_level, listen_addresses.clone()) {
+        Ok(config) => config,
+        Err(e) => return config_error(&e),
+    };
+    if let Err(e) = logging::init(&config.logging) {
+        eprintln!("error: cannot set up logging: {}", e);
+        return ExitCode::from(EXIT_FAILURE);
+    }
+
+    let access = Arc::new(AccessLists::new(&config));
+    let authenticator = Authenticator::new(&config.auth).map(Arc::new);
+    let mut builder = StunServerBuilder::new();
+    if let Some(authenticator) = &authenticator {
+        builder = builder.authenticator(authenticator.clone());
+    }
+    let server = match builder.bind_config(&config, &access).await {
+        Ok(server) => server,
+        Err(e) => return report(Err(e)),
+    };
+    tokio::spawn(reload_config(
+        config_path,
+        log_level,
+        listen_addresses,
+        access,
+        authenticator,
+    ));
+    let shutdown = Shutdown::new(Duration::from_secs(config.shutdown.drain_timeout));
+    let run = server.run(shutdown.clone());
+    tokio::pin!(run);
+    tokio::select! {
+        result = &mut run => return stopped(result),
+        signal = shutdown_signal() => {
+            if let Err(e) = signal {
+                return report(Err(e.into()));
+            }
+        }
+    }
+
+    shutdown.begin();
+    if config.health.listen.is_some() || config.redirect.on_shutdown {
+        // Keep serving while load balancers notice /readyz failing and clients are redirected.
+        let delay = Duration::from_secs(config.health.shutdown_delay);
+        tokio::select! {
+            result = &mut run => return stopped(result),
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+
+    info!(drain_timeout = ?shutdown.drain_timeout(), "stopping listeners and draining connections");
+    shutdown.trigger();
+    tokio::select! {
+        result = &mut run => stopped(result),
+        _ = shutdown_signal() => {
+            warn!("second signal received, exiting without draining");
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn stopped(result: Result<ShutdownSummary, Box<dyn Error>>) -> ExitCode {
+    report(result.map(|summary| {
+        info!(
+            udp_requests = summary.udp_requests,
+            connections = summary.connections,
+            aborted_connections = summary.aborted_connections,
+            "server stopped"
+        );
+    }))
+}
+
+/// Resolves on SIGTERM or SIGINT.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok(()),
+            result = tokio::signal::ctrl_c() => result,
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// Reloads the access lists and nonce secrets on SIGHUP or when the config file changes.
+/// Other settings only take effect after a restart.
+async fn reload_config(
+    config_path: Option<PathBuf>,
+    log_level: Option<LogLevel>,
+    listen_addresses: Option<Vec<ListenAddress>>,
+    access: Arc<AccessLists>,
+    authenticator: Option<Arc<Authenticator>>,
+) {
+    let watched = config_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&watched);
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            warn!(error = %e, "cannot listen for SIGHUP, config reloads on file changes only");
+            None
+        }
+    };
+    #[cfg(not(unix))]
+    let mut hangup = None;
+    let mut poll = tokio::time::interval(CONFIG_POLL_INTERVAL);
+    loop {
+        let reason = tokio::select! {
+            _ = next_hangup(&mut hangup) => "SIGHUP",
+            _ = poll.tick() => {
+                let current = modified(&watched);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                "config file changed"
+            }
+        };
+        match load_config(config_path.clone(), log_level, listen_addresses.clone()) {
+            Ok(config) => {
+                access.reload(&config);
+                if let Some(authenticator) = &authenticator {
+                    authenticator.reload_nonce_secrets(&config.auth);
+                }
+                info!(reason, "reloaded access lists and nonce secrets");
+            }
+            Err(e) => warn!(
+                reason,
+                error = %e,
+                "invalid configuration, keeping access lists and nonce secrets"
+            ),
+        }
+    }
+}
+
+/// Resolves on the next SIGHUP, or never when there is no signal to wait for.
+#[cfg(unix)]
+async fn next_hangup(hangup: &mut Option<tokio::signal::unix::Signal>) {
+    if let Some(signal) = hangup {
+        if signal.recv().await.is_some() {
+            return;
+        }
+        *hangup = None;
+    }
+    futures::future::pending().await
+}
+
+#[cfg(not(unix))]
+async fn next_hangup(_: &mut Option<()>) {
+    futures::future::pending().await
+}
+
+/// Merges the config file, `STUN_*` environment variables and command line flags, in that
+/// order, and validates the result.
+fn load_config(
+    config_path: Option<PathBuf>,
+    log_level: Option<LogLevel>,
+    listen_addresses: Option<Vec<ListenAddress>>,
+) -> Result<Config, ConfigError> {
+    let mut config = Config::load(config_path.as_deref())?;
+    config.apply_env(std::env::vars())?;
+    if let Some(log_level) = log_level {
+        config.logging.level = log_level;
+    }
+    if let Some(listen_addresses) = listen_addresses {
+        config.listen = listen_addresses;
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+async fn client(client_args: ClientArgs) -> Result<(), Box<dyn Error>> {
+    let server = resolve_server(&client_args.server).await?;
+    let response = match client_args.transport {
+        ClientTransportArg::Udp => binding_request(server, ClientTransport::Udp).await?,
+        ClientTransportArg::Tcp => binding_request(server, ClientTransport::Tcp).await?,
+        ClientTransportArg::Tls => {
+            let server_name = match client_args.server_name {
+                Some(server_name) => server_name,
+                None => host_name(&client_args.server)
+                    .ok_or("--server-name is required when SERVER is an IP address")?,
+            };
+            let roots = match &client_args.ca_file {
+                Some(path) => stun::tls::load_roots(path)?,
+                None => stun::tls::system_roots()?,
+            };
+            binding_request_tls(server, TlsClient::new(&server_name, roots)?).await?
+        }
+    };
+    if response.server != server {
+        println!("redirected to: {}", response.server);
+    }
+    println!("mapped address: {}", response.mapped_address);
+    if let Some(response_origin) = response.response_origin {
+        println!("response origin: {}", response_origin);
+    }
+    Ok(())
+}
+
+async fn resolve_server(server: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    if let Ok(address) = server.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DEFAULT_PORT));
+    }
+    let host = match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => server.to_owned(),
+        _ => format!("{}:{}", server, DEFAULT_PORT),
+    };
+    tokio::net::lookup_host(host)
+        .await?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to any address", server).into())
+}
+
+/// The host name in a `host[:port]` server argument; `None` for IP addresses.
+fn host_name(server: &str) -> Option<String> {
+    if server.parse::<SocketAddr>().is_ok() || server.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    let host = match server.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => server,
+    };
+    Some(host.to_owned())
+}
+
+fn decode(decode_args: DecodeArgs) -> Result<(), Box<dyn Error>> {
+    let hex = match decode_args.hex {
+        Some(hex) => hex,
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+    let bytes = parse_hex(&hex)?;
+    let message = DecodedMessage::decode(&bytes)?;
+
+    println!(
+        "{} {} ({:#06x}), length {}, transaction {}",
+        message.method(),
+        message.class(),
+        message.type_,
+        message.length,
+        to_hex(&message.transaction_id)
+    );
+    for attribute in &message.attributes {
+        let value = match attribute.type_ {
+            XOR_MAPPED_ADDRESS => decode_xor_address(&attribute.value, &message.transaction_id)
+                .map(|address| address.to_string()),
+            MAPPED_ADDRESS | RESPONSE_ORIGIN | ALTERNATE_SERVER => {
+                decode_address(&attribute.value).map(|address| address.to_string())
+            }
+            ALTERNATE_DOMAIN => String::from_utf8(attribute.value.clone()).ok(),
+            _ => None,
+        };
+        println!(
+            "  {} ({:#06x}): {}",
+            attribute_name(attribute.type_),
+            attribute.type_,
+            value.unwrap_or_else(|| to_hex(&attribute.value))
+        );
+    }
+    Ok(())
+}
+
+fn parse_hex(input: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = input
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("hex input has an odd number of digits".to_owned());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex digits {:?}", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+fn config_error(error: &ConfigError) -> ExitCode {
+    eprintln!("invalid configuration: {}", error);
+    ExitCode::from(EXIT_CONFIG)
+}
+
+fn report(result: Result<(), Box<dyn Error>>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(input: &[&str]) -> Vec<String> {
+        input.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_legacy_positional_form() {
+        let listen = legacy_listen_addresses(&args(&["stun", "0.0.0.0", "3479", "udp"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(listen[0].address, "0.0.0.0:3479".parse().unwrap());
+        assert_eq!(listen[0].server_type, StunServerEnum::UdpStunServer);
+
+        assert!(legacy_listen_addresses(&args(&["stun", "serve"])).is_none());
+        assert!(legacy_listen_addresses(&args(&["stun", "--listen", "0.0.0.0"])).is_none());
+        assert!(legacy_listen_addresses(&args(&["stun", "0.0.0.0", "x"]))
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_named_flags() {
+        let cli = Cli::try_parse_from(args(&[
+            "stun",
+            "serve",
+            "--listen",
+            "0.0.0.0",
+            "--listen",
+            "[::]:3479/tcp",
+            "--transport",
+            "udp",
+            "--log-level",
+            "debug",
+        ]))
+        .unwrap();
+        let serve_args = match cli.command {
+            Some(Command::Serve(serve_args)) => serve_args,
+            other => panic!("unexpected command {:?}", other),
+        };
+        let listen = listen_from_args(&serve_args).unwrap().unwrap();
+        assert_eq!(listen[0].address, "0.0.0.0:3478".parse().unwrap());
+        assert_eq!(listen[0].server_type, StunServerEnum::UdpStunServer);
+        assert_eq!(listen[1].server_type, StunServerEnum::TcpStunServer);
+        assert_eq!(cli.log_level, Some(LogLevel::Debug));
+
+        assert!(Cli::try_parse_from(args(&["stun", "--transport", "sctp"])).is_err());
+        assert!(Cli::try_parse_from(args(&["stun", "client"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("00 01\n0a ff"), Ok(vec![0x00, 0x01, 0x0a, 0xff]));
+        assert!(parse_hex("0").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_host_name() {
+        assert_eq!(
+            host_name("stun.example.org:5349").as_deref(),
+            Some("stun.example.org")
+        );
+        assert_eq!(host_name("localhost").as_deref(), Some("localhost"));
+        assert_eq!(host_name("192.0.2.1:5349"), None);
+        assert_eq!(host_name("2001:db8::1"), None);
+    }
+}
@@ -0,0 +1,369 @@
+//! A minimal STUN client: sends a Binding request and reports the mapped address.
+//!
+//! Redirects with 300 Try Alternate are followed. Over TLS the alternate's certificate must
+//! be for the name in ALTERNATE-DOMAIN, or for the original name when there is none.
+use crate::attributes::{
+    decode_address, decode_xor_address, ALTERNATE_DOMAIN, ALTERNATE_SERVER, ERROR_CODE,
+    MAPPED_ADDRESS, RESPONSE_ORIGIN, XOR_MAPPED_ADDRESS,
+};
+use crate::handlers::{BINDING_ERROR_RESPONSE, BINDING_RESPONSE};
+use crate::message::{Class, DecodedMessage, Method, StunMessage, HEADER_LENGTH};
+use byteorder::{BigEndian, ByteOrder};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::TlsConnector;
+
+/// RTO for the first UDP transmission, doubled on every retransmission (RFC 5389 7.2.1).
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const UDP_TRANSMISSIONS: u32 = 4;
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Redirects followed before giving up.
+const MAX_REDIRECTS: usize = 2;
+const TRY_ALTERNATE: u16 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientTransport {
+    Udp,
+    Tcp,
+}
+
+/// How the certificate of a TLS server is checked.
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl TlsClient {
+    /// Trusts certificates issued by `roots` for `server_name`.
+    pub fn new(server_name: &str, roots: RootCertStore) -> Result<Self, Box<dyn Error>> {
+        Ok(TlsClient {
+            connector: crate::tls::connector(roots)?,
+            server_name: ServerName::try_from(server_name.to_owned())?,
+        })
+    }
+}
+
+/// The server answered with a Binding error response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorResponse {
+    /// The ERROR-CODE, e.g. 401; `None` when the response has none.
+    pub code: Option<u16>,
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "server returned a Binding error response ({})", code),
+            None => write!(f, "server returned a Binding error response"),
+        }
+    }
+}
+
+impl Error for ErrorResponse {}
+
+enum Connection {
+    Plain(ClientTransport),
+    Tls(TlsClient),
+}
+
+#[derive(Debug)]
+pub struct BindingResponse {
+    /// The server that answered, which differs from the one asked when it redirected.
+    pub server: SocketAddr,
+    /// The reflexive transport address the server saw the request come from.
+    pub mapped_address: SocketAddr,
+    /// The address the server sent the response from, if it reported one.
+    pub response_origin: Option<SocketAddr>,
+    pub message: DecodedMessage,
+}
+
+/// Sends a Binding request to `server` and waits for the matching response.
+pub async fn binding_request(
+    server: SocketAddr,
+    transport: ClientTransport,
+) -> Result<BindingResponse, Box<dyn Error>> {
+    request(server, Connection::Plain(transport)).await
+}
+
+/// Sends a Binding request to `server` over TLS, checking its certificate with `tls`.
+pub async fn binding_request_tls(
+    server: SocketAddr,
+    tls: TlsClient,
+) -> Result<BindingResponse, Box<dyn Error>> {
+    request(server, Connection::Tls(tls)).await
+}
+
+async fn request(
+    mut server: SocketAddr,
+    mut connection: Connection,
+) -> Result<BindingResponse, Box<dyn Error>> {
+    let mut visited = vec![server];
+    loop {
+        let transaction_id = new_transaction_id()?;
+        let request = StunMessage::builder(Method::BINDING, Class::Request, transaction_id)
+            .build()
+            .into_bytes();
+
+        let response = match &connection {
+            Connection::Plain(ClientTransport::Udp) => {
+                exchange_udp(server, &request, &transaction_id).await?
+            }
+            Connection::Plain(ClientTransport::Tcp) => exchange_tcp(server, &request).await?,
+            Connection::Tls(tls) => exchange_tls(server, &request, tls).await?,
+        };
+        let message = DecodedMessage::decode(&response)?;
+        if message.transaction_id != transaction_id {
+            return Err("response does not match the request's transaction ID".into());
+        }
+        if message.type_ == BINDING_ERROR_RESPONSE {
+            let code = error_code(&message);
+            let alternate = message
+                .attribute(ALTERNATE_SERVER)
+                .and_then(|attribute| decode_address(&attribute.value));
+            let alternate = match (code, alternate) {
+                (Some(TRY_ALTERNATE), Some(alternate)) => alternate,
+                (code, _) => return Err(ErrorResponse { code }.into()),
+            };
+            if visited.contains(&alternate) || visited.len() > MAX_REDIRECTS {
+                return Err(format!("too many redirects, last to {}", alternate).into());
+            }
+            if let Connection::Tls(tls) = &mut connection {
+                if let Some(domain) = message.attribute(ALTERNATE_DOMAIN) {
+                    let domain = String::from_utf8(domain.value.clone())?;
+                    tls.server_name = ServerName::try_from(domain)?;
+                }
+            }
+            server = alternate;
+            visited.push(server);
+            continue;
+        }
+        if message.type_ != BINDING_RESPONSE {
+            return Err(format!("unexpected message type {:#06x}", message.type_).into());
+        }
+        return binding_response(server, message, &transaction_id);
+    }
+}
+
+fn binding_response(
+    server: SocketAddr,
+    message: DecodedMessage,
+    transaction_id: &[u8; 12],
+) -> Result<BindingResponse, Box<dyn Error>> {
+    let mapped_address = message
+        .attribute(XOR_MAPPED_ADDRESS)
+        .and_then(|attribute| decode_xor_address(&attribute.value, transaction_id))
+        .or_else(|| {
+            message
+                .attribute(MAPPED_ADDRESS)
+                .and_then(|attribute| decode_address(&attribute.value))
+        })
+        .ok_or("response has no mapped address")?;
+    let response_origin = message
+        .attribute(RESPONSE_ORIGIN)
+        .and_then(|attribute| decode_address(&attribute.value));
+
+    Ok(BindingResponse {
+        server,
+        mapped_address,
+        response_origin,
+        message,
+    })
+}
+
+/// The number of the ERROR-CODE attribute, e.g. 300.
+fn error_code(message: &DecodedMessage) -> Option<u16> {
+    let value = &message.attribute(ERROR_CODE)?.value;
+    if value.len() < 4 {
+        return None;
+    }
+    Some(u16::from(value[2] & 0x07) * 100 + u16::from(value[3]))
+}
+
+fn new_transaction_id() -> Result<[u8; 12], Box<dyn Error>> {
+    let mut transaction_id = [0_u8; 12];
+    getrandom::getrandom(&mut transaction_id)?;
+    Ok(transaction_id)
+}
+
+async fn exchange_udp(
+    server: SocketAddr,
+    request: &[u8],
+    transaction_id: &[u8; 12],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+
+    let mut buffer = [0_u8; 1024];
+    let mut rto = INITIAL_RTO;
+    for _ in 0..UDP_TRANSMISSIONS {
+        socket.send(request).await?;
+        let deadline = tokio::time::Instant::now() + rto;
+        // Ignore stray datagrams until the retransmission timer fires.
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+            let length = received?;
+            if length >= HEADER_LENGTH && &buffer[8..20] == transaction_id {
+                return Ok(buffer[..length].to_vec());
+            }
+        }
+        rto *= 2;
+    }
+    Err(format!(
+        "no response from {} after {} attempts",
+        server, UDP_TRANSMISSIONS
+    )
+    .into())
+}
+
+async fn exchange_tcp(server: SocketAddr, request: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let exchange = async {
+        let stream = TcpStream::connect(server).await?;
+        exchange_stream(stream, request).await
+    };
+    timeout(TCP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| format!("no response from {}", server))?
+}
+
+async fn exchange_tls(
+    server: SocketAddr,
+    request: &[u8],
+    tls: &TlsClient,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let exchange = async {
+        let stream = TcpStream::connect(server).await?;
+        let stream = tls
+            .connector
+            .connect(tls.server_name.clone(), stream)
+            .await?;
+        exchange_stream(stream, request).await
+    };
+    timeout(TCP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| format!("no response from {}", server))?
+}
+
+async fn exchange_stream<S>(mut stream: S, request: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request).await?;
+
+    let mut response = vec![0_u8; HEADER_LENGTH];
+    stream.read_exact(&mut response).await?;
+    let length = BigEndian::read_u16(&response[2..4]) as usize;
+    response.resize(HEADER_LENGTH + length, 0);
+    stream.read_exact(&mut response[HEADER_LENGTH..]).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stunserver::StunServerBuilder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_binding_request() {
+        let config = crate::config::Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Default::default()
+        };
+        let server = StunServerBuilder::new().spawn(&config).await.unwrap();
+        let server_address = server.local_addresses()[0].address;
+
+        for &transport in &[ClientTransport::Udp, ClientTransport::Tcp] {
+            let response = binding_request(server_address, transport).await.unwrap();
+            assert_eq!(response.mapped_address.ip(), server_address.ip());
+            assert_eq!(response.response_origin, Some(server_address));
+        }
+    }
+
+    /// Starts a TLS listener with a certificate for `name`, returning its address and
+    /// certificate.
+    async fn tls_server(
+        name: &str,
+        redirect: crate::config::RedirectConfig,
+    ) -> (
+        SocketAddr,
+        tokio_rustls::rustls::pki_types::CertificateDer<'static>,
+    ) {
+        use crate::config::{Config, TlsConfig};
+
+        static SERVERS: AtomicUsize = AtomicUsize::new(0);
+        let certified = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        let directory = std::env::temp_dir().join(format!(
+            "stun-client-test-{}-{}",
+            std::process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let tls = TlsConfig {
+            certificate: directory.join("cert.pem"),
+            private_key: directory.join("key.pem"),
+        };
+        std::fs::write(&tls.certificate, certified.cert.pem()).unwrap();
+        std::fs::write(&tls.private_key, certified.key_pair.serialize_pem()).unwrap();
+        let config = Config {
+            listen: vec!["127.0.0.1:0/tls".parse().unwrap()],
+            tls: Some(tls),
+            redirect,
+            ..Config::default()
+        };
+        let server = StunServerBuilder::new().spawn(&config).await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        (
+            server.local_addresses()[0].address,
+            certified.cert.der().clone(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tls_redirect_checks_alternate_domain() {
+        use crate::config::{RedirectConfig, TlsAlternate};
+
+        let (alternate, alternate_certificate) =
+            tls_server("stun-b.example.org", RedirectConfig::default()).await;
+        let redirecting = |domain: &str| RedirectConfig {
+            tls_servers: vec![TlsAlternate {
+                address: alternate,
+                domain: domain.to_owned(),
+            }],
+            // Every request after the first is over the limit.
+            max_requests_per_second: Some(1),
+            unauthenticated: true,
+            ..RedirectConfig::default()
+        };
+        let (server, certificate) =
+            tls_server("localhost", redirecting("stun-b.example.org")).await;
+        let (misnamed, misnamed_certificate) =
+            tls_server("localhost", redirecting("stun-c.example.org")).await;
+        let client = || {
+            let mut roots = RootCertStore::empty();
+            for certificate in &[&alternate_certificate, &certificate, &misnamed_certificate] {
+                roots.add((*certificate).clone()).unwrap();
+            }
+            TlsClient::new("localhost", roots).unwrap()
+        };
+
+        let response = binding_request_tls(server, client()).await.unwrap();
+        assert_eq!(response.server, server);
+        let response = binding_request_tls(server, client()).await.unwrap();
+        assert_eq!(response.server, alternate);
+
+        binding_request_tls(misnamed, client()).await.unwrap();
+        assert!(binding_request_tls(misnamed, client()).await.is_err());
+    }
+}
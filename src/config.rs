@@ -0,0 +1,792 @@
+//! Server configuration, read from `stun.toml`.
+//!
+//! Values are layered: defaults, then the config file, then `STUN_*` environment variables,
+//! then command line arguments. `Config::validate` checks the merged result, and every error
+//! names the key (or environment variable) it is about.
+//!
+//! ```toml
+//! listen = ["0.0.0.0:3478", "[::]:3478", "0.0.0.0:5349/tls"]
+//! software = "stun/0.1.0"
+//!
+//! [tls]
+//! certificate = "/etc/stun/cert.pem"
+//! private_key = "/etc/stun/key.pem"
+//!
+//! [auth]
+//! mode = "long-term"
+//! realm = "example.org"
+//! users = { alice = "secret" }
+//!
+//! [logging]
+//! level = "info"
+//! format = "text"
+//!
+//! [access."0.0.0.0:3478"]
+//! allow = ["10.0.0.0/8"]
+//!
+//! [proxy_protocol."0.0.0.0:5349/tls"]
+//! trusted = ["10.0.0.0/8"]
+//!
+//! [redirect]
+//! servers = ["198.51.100.2:3478"]
+//! on_shutdown = true
+//! ```
+use crate::access::Cidr;
+use crate::shutdown::DEFAULT_DRAIN_TIMEOUT;
+use crate::stunserver::{ListenAddress, StunServerEnum};
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const DEFAULT_CONFIG_PATH: &str = "stun.toml";
+
+/// RFC 5389 limits SOFTWARE to fewer than 128 characters.
+const MAX_SOFTWARE_LENGTH: usize = 127;
+/// RFC 5389 limits REALM to fewer than 128 characters.
+const MAX_REALM_LENGTH: usize = 127;
+/// RFC 8489 limits ALTERNATE-DOMAIN to fewer than 256 bytes.
+const MAX_DOMAIN_LENGTH: usize = 255;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<ListenAddress>,
+    /// SOFTWARE attribute of responses. Empty to leave it out.
+    pub software: String,
+    pub tls: Option<TlsConfig>,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub logging: LoggingConfig,
+    pub metrics: MetricsConfig,
+    pub health: HealthConfig,
+    pub shutdown: ShutdownConfig,
+    pub turn: TurnConfig,
+    /// Access lists keyed by the `listen` entry they apply to.
+    pub access: BTreeMap<String, AccessConfig>,
+    /// PROXY protocol settings keyed by the TCP or TLS `listen` entry they apply to.
+    pub proxy_protocol: BTreeMap<String, ProxyProtocolConfig>,
+    pub redirect: RedirectConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![ListenAddress {
+                address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 3478),
+                server_type: StunServerEnum::MultiplexedStunServer,
+            }],
+            software: concat!("stun/", env!("CARGO_PKG_VERSION")).to_owned(),
+            tls: None,
+            auth: AuthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            logging: LoggingConfig::default(),
+            metrics: MetricsConfig::default(),
+            health: HealthConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            turn: TurnConfig::default(),
+            access: BTreeMap::new(),
+            proxy_protocol: BTreeMap::new(),
+            redirect: RedirectConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first.
+    pub certificate: PathBuf,
+    /// PEM file with the PKCS#8, PKCS#1 or SEC1 private key.
+    pub private_key: PathBuf,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    /// REALM of the long-term credential mechanism.
+    pub realm: Option<String>,
+    /// Passwords by username.
+    pub users: BTreeMap<String, String>,
+    /// Algorithms offered for long-term keys in PASSWORD-ALGORITHMS, preferred first.
+    pub password_algorithms: Vec<PasswordAlgorithm>,
+    /// Seconds a nonce is accepted for before requests get 438 Stale Nonce.
+    pub nonce_lifetime: u64,
+    /// Secrets nonces are signed with. The first signs new nonces, the others are still
+    /// accepted so that a secret can be rotated: prepend the new one and keep the old one
+    /// for a nonce lifetime. Random per process, with a warning, when empty. Reloaded with
+    /// the access lists.
+    pub nonce_secrets: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            mode: AuthMode::None,
+            realm: None,
+            users: BTreeMap::new(),
+            password_algorithms: vec![PasswordAlgorithm::Sha256, PasswordAlgorithm::Md5],
+            nonce_lifetime: 600,
+            nonce_secrets: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMode {
+    #[default]
+    None,
+    ShortTerm,
+    LongTerm,
+}
+
+/// How long-term keys are derived from passwords (RFC 8489 section 18.5).
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PasswordAlgorithm {
+    Md5,
+    #[serde(rename = "sha-256")]
+    Sha256,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Requests per second allowed from a single source address.
+    pub per_ip: Option<u32>,
+    /// Requests per second allowed from a /24 (IPv4) or /64 (IPv6) prefix.
+    pub per_prefix: Option<u32>,
+    /// Requests a source may send in a burst above its rate.
+    pub burst: Option<u32>,
+    /// Error responses sent per second over UDP, across all sources. Defaults to 100.
+    pub error_responses: Option<u32>,
+    /// Error responses sent per second over UDP to one /24 (IPv4) or /64 (IPv6) prefix.
+    /// Defaults to 10.
+    pub error_responses_per_prefix: Option<u32>,
+}
+
+/// When Binding requests are answered with 300 Try Alternate and an ALTERNATE-SERVER.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    /// Servers UDP and TCP clients are sent to. Each client is sent to one chosen by a hash
+    /// of its address, among those of its address family.
+    pub servers: Vec<SocketAddr>,
+    /// Servers TLS clients are sent to, with the name their certificates are checked against.
+    pub tls_servers: Vec<TlsAlternate>,
+    /// Redirect while shutting down, for `health.shutdown_delay` seconds before the server
+    /// stops.
+    pub on_shutdown: bool,
+    /// Redirect when more than this many Binding requests arrive in a second.
+    pub max_requests_per_second: Option<u32>,
+    /// Keep only the clients whose address hashes to this server, sharing the rest out
+    /// evenly among `servers`.
+    pub shard: bool,
+    /// Also redirect requests whose credentials were not verified. Not RFC compliant: RFC 5389
+    /// and RFC 8489 only allow a 300 in answer to a request with valid MESSAGE-INTEGRITY.
+    pub unauthenticated: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsAlternate {
+    pub address: SocketAddr,
+    /// Sent as ALTERNATE-DOMAIN.
+    pub domain: String,
+}
+
+impl RedirectConfig {
+    /// Whether any condition for redirecting is set.
+    pub fn is_enabled(&self) -> bool {
+        self.on_shutdown || self.max_requests_per_second.is_some() || self.shard
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Hex dump one in this many packets at debug level. 0 disables dumps.
+    pub packet_dump_every: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address of the HTTP `/metrics` endpoint. Disabled when unset.
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Address of the HTTP `/healthz` and `/readyz` endpoints. Disabled when unset.
+    pub listen: Option<SocketAddr>,
+    /// Seconds between reporting not ready on SIGTERM and stopping. Also applies when only
+    /// `redirect.on_shutdown` is set.
+    pub shutdown_delay: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            listen: None,
+            shutdown_delay: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds open TCP and TLS connections get to finish after the listeners stop.
+    pub drain_timeout: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TurnConfig {
+    pub enabled: bool,
+    pub relay_address: Option<IpAddr>,
+    pub min_port: Option<u16>,
+    pub max_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Networks allowed to use the listener. Everyone is allowed when empty.
+    pub allow: Vec<Cidr>,
+    /// Networks refused even when they are in `allow`.
+    pub deny: Vec<Cidr>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    /// Load balancers whose connections start with a PROXY header.
+    pub trusted: Vec<Cidr>,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        parse_variant(value)
+    }
+}
+
+/// A configuration problem, tied to the key or environment variable that caused it.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: &str, message: impl Into<String>) -> Self {
+        ConfigError {
+            key: key.to_owned(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let listen = String::deserialize(deserializer)?;
+        listen.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Config {
+    /// Reads the file at `path`, or `stun.toml` in the working directory if it exists.
+    /// Without a file the defaults are used.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(Config::default()),
+        };
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::new(&path.display().to_string(), e.to_string()))?;
+        Config::parse(&contents).map_err(|e| ConfigError {
+            message: format!("{} (in {})", e.message, path.display()),
+            ..e
+        })
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+        serde_path_to_error::deserialize(toml::Deserializer::new(contents)).map_err(|e| {
+            let key = match e.path().to_string() {
+                path if path == "." => String::new(),
+                path => path,
+            };
+            let error = e.into_inner();
+            let message = match error.span() {
+                Some(span) => format!(
+                    "{} (line {})",
+                    error.message(),
+                    contents[..span.start].lines().count().max(1)
+                ),
+                None => error.message().to_owned(),
+            };
+            ConfigError::new(&key, message)
+        })
+    }
+
+    /// Overrides values with `STUN_*` environment variables.
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), ConfigError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (name, value) in vars {
+            let error = |message: String| ConfigError::new(&name, message);
+            match name.as_str() {
+                "STUN_LISTEN" => {
+                    self.listen = value
+                        .split(',')
+                        .map(|listen| listen.trim().parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(error)?;
+                }
+                "STUN_SOFTWARE" => self.software = value,
+                "STUN_TLS_CERTIFICATE" => self.tls_mut().certificate = value.into(),
+                "STUN_TLS_PRIVATE_KEY" => self.tls_mut().private_key = value.into(),
+                "STUN_AUTH_MODE" => self.auth.mode = parse_variant(&value).map_err(error)?,
+                "STUN_AUTH_REALM" => self.auth.realm = Some(value),
+                "STUN_AUTH_NONCE_LIFETIME" => {
+                    self.auth.nonce_lifetime = value.parse().map_err(|e| error(format!("{}", e)))?
+                }
+                "STUN_AUTH_NONCE_SECRETS" => {
+                    self.auth.nonce_secrets = value
+                        .split(',')
+                        .map(|secret| secret.trim().to_owned())
+                        .collect()
+                }
+                "STUN_RATE_LIMIT_PER_IP" => {
+                    self.rate_limit.per_ip =
+                        Some(value.parse().map_err(|e| error(format!("{}", e)))?)
+                }
+                "STUN_RATE_LIMIT_PER_PREFIX" => {
+                    self.rate_limit.per_prefix =
+                        Some(value.parse().map_err(|e| error(format!("{}", e)))?)
+                }
+                "STUN_RATE_LIMIT_BURST" => {
+                    self.rate_limit.burst =
+                        Some(value.parse().map_err(|e| error(format!("{}", e)))?)
+                }
+                "STUN_RATE_LIMIT_ERROR_RESPONSES" => {
+                    self.rate_limit.error_responses =
+                        Some(value.parse().map_err(|e| error(format!("{}", e)))?)
+                }
+                "STUN_RATE_LIMIT_ERROR_RESPONSES_PER_PREFIX" => {
+                    self.rate_limit.error_responses_per_prefix =
+                        Some(value.parse().map_err(|e| error(format!("{}", e)))?)
+                }
+                "STUN_REDIRECT_SERVERS" => {
+                    self.redirect.servers = value
+                        .split(',')
+                        .map(|server| server.trim().parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| error(format!("{}", e)))?;
+                }
+                "STUN_REDIRECT_ON_SHUTDOWN" => {
+                    self.redirect.on_shutdown =
+                        value.parse().map_err(|e| error(format!("{}", e)))?
+                }
+                "STUN_LOG_LEVEL" => self.logging.level = parse_variant(&value).map_err(error)?,
+                "STUN_LOG_FORMAT" => self.logging.format = parse_variant(&value).map_err(error)?,
+                "STUN_LOG_PACKET_DUMP_EVERY" => {
+                    self.logging.packet_dump_every =
+                        value.parse().map_err(|e| error(format!("{}", e)))?
+                }
+                "STUN_METRICS_LISTEN" => {
+                    self.metrics.listen = Some(value.parse().map_err(|e| error(format!("{}", e)))?)
+                }
+                "STUN_HEALTH_LISTEN" => {
+                    self.health.listen = Some(value.parse().map_err(|e| error(format!("{}", e)))?)
+                }
+                "STUN_HEALTH_SHUTDOWN_DELAY" => {
+                    self.health.shutdown_delay =
+                        value.parse().map_err(|e| error(format!("{}", e)))?
+                }
+                "STUN_SHUTDOWN_DRAIN_TIMEOUT" => {
+                    self.shutdown.drain_timeout =
+                        value.parse().map_err(|e| error(format!("{}", e)))?
+                }
+                "STUN_TURN_ENABLED" => {
+                    self.turn.enabled = value.parse().map_err(|e| error(format!("{}", e)))?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the merged configuration for values the server cannot run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(ConfigError::new(
+                "listen",
+                "at least one listen address is required",
+            ));
+        }
+        if self.tls.is_none()
+            && self
+                .listen
+                .iter()
+                .any(|listen| listen.server_type == StunServerEnum::TlsStunServer)
+        {
+            return Err(ConfigError::new(
+                "tls",
+                "a TLS listener needs tls.certificate and tls.private_key",
+            ));
+        }
+        if let Some(tls) = &self.tls {
+            if tls.certificate.as_os_str().is_empty() {
+                return Err(ConfigError::new("tls.certificate", "must be set"));
+            }
+            if tls.private_key.as_os_str().is_empty() {
+                return Err(ConfigError::new("tls.private_key", "must be set"));
+            }
+        }
+        if self.software.chars().count() > MAX_SOFTWARE_LENGTH {
+            return Err(ConfigError::new(
+                "software",
+                format!("must be at most {} characters", MAX_SOFTWARE_LENGTH),
+            ));
+        }
+        if let Some(realm) = &self.auth.realm {
+            if realm.chars().count() > MAX_REALM_LENGTH {
+                return Err(ConfigError::new(
+                    "auth.realm",
+                    format!("must be at most {} characters", MAX_REALM_LENGTH),
+                ));
+            }
+        }
+        if self.auth.mode != AuthMode::None && self.auth.users.is_empty() {
+            return Err(ConfigError::new(
+                "auth.users",
+                "must list at least one user",
+            ));
+        }
+        if self.auth.mode == AuthMode::LongTerm {
+            if self.auth.realm.as_deref().unwrap_or_default().is_empty() {
+                return Err(ConfigError::new(
+                    "auth.realm",
+                    "the long-term credential mechanism needs a realm",
+                ));
+            }
+            let algorithms = &self.auth.password_algorithms;
+            if algorithms.is_empty()
+                || algorithms
+                    .iter()
+                    .enumerate()
+                    .any(|(index, algorithm)| algorithms[..index].contains(algorithm))
+            {
+                return Err(ConfigError::new(
+                    "auth.password_algorithms",
+                    "must list each algorithm at most once, and at least one",
+                ));
+            }
+            if self.auth.nonce_lifetime == 0 {
+                return Err(ConfigError::new(
+                    "auth.nonce_lifetime",
+                    "must be at least 1 second",
+                ));
+            }
+            if self.auth.nonce_secrets.iter().any(String::is_empty) {
+                return Err(ConfigError::new(
+                    "auth.nonce_secrets",
+                    "must not contain empty secrets",
+                ));
+            }
+        }
+        for (key, value) in &[
+            ("rate_limit.per_ip", self.rate_limit.per_ip),
+            ("rate_limit.per_prefix", self.rate_limit.per_prefix),
+            ("rate_limit.burst", self.rate_limit.burst),
+            (
+                "rate_limit.error_responses",
+                self.rate_limit.error_responses,
+            ),
+            (
+                "rate_limit.error_responses_per_prefix",
+                self.rate_limit.error_responses_per_prefix,
+            ),
+            (
+                "redirect.max_requests_per_second",
+                self.redirect.max_requests_per_second,
+            ),
+        ] {
+            if *value == Some(0) {
+                return Err(ConfigError::new(key, "must be at least 1"));
+            }
+        }
+        if self.redirect.is_enabled()
+            && self.redirect.servers.is_empty()
+            && self.redirect.tls_servers.is_empty()
+        {
+            return Err(ConfigError::new(
+                "redirect.servers",
+                "must list at least one server to redirect to",
+            ));
+        }
+        for (index, alternate) in self.redirect.tls_servers.iter().enumerate() {
+            if alternate.domain.is_empty() || alternate.domain.len() > MAX_DOMAIN_LENGTH {
+                return Err(ConfigError::new(
+                    &format!("redirect.tls_servers[{}].domain", index),
+                    format!("must be 1 to {} bytes long", MAX_DOMAIN_LENGTH),
+                ));
+            }
+        }
+        if cfg!(not(feature = "metrics")) && self.metrics.listen.is_some() {
+            return Err(ConfigError::new(
+                "metrics.listen",
+                "this build has no metrics endpoint (enable the `metrics` feature)",
+            ));
+        }
+        self.validate_listener_keys("access", self.access.keys())?;
+        self.validate_listener_keys("proxy_protocol", self.proxy_protocol.keys())?;
+        for (key, proxy_protocol) in &self.proxy_protocol {
+            let listen: ListenAddress = key.parse().map_err(|e| ConfigError::new(key, e))?;
+            let key = format!("proxy_protocol.{:?}", key);
+            if listen.server_type == StunServerEnum::UdpStunServer {
+                return Err(ConfigError::new(
+                    &key,
+                    "the PROXY protocol needs a TCP or TLS listener",
+                ));
+            }
+            if proxy_protocol.trusted.is_empty() {
+                return Err(ConfigError::new(
+                    &format!("{}.trusted", key),
+                    "must list at least one proxy network",
+                ));
+            }
+        }
+        if let (Some(min_port), Some(max_port)) = (self.turn.min_port, self.turn.max_port) {
+            if min_port > max_port {
+                return Err(ConfigError::new(
+                    "turn.min_port",
+                    "must not exceed turn.max_port",
+                ));
+            }
+        }
+        if self.turn.enabled {
+            return Err(ConfigError::new(
+                "turn.enabled",
+                "TURN relaying is not supported by this server",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The access list of `listen`, or an empty one that allows everyone.
+    pub fn access_for(&self, listen: &ListenAddress) -> AccessConfig {
+        for_listener(&self.access, listen)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The PROXY protocol settings of `listen`; `None` when it is not behind a proxy.
+    pub fn proxy_protocol_for(&self, listen: &ListenAddress) -> Option<&ProxyProtocolConfig> {
+        for_listener(&self.proxy_protocol, listen)
+    }
+
+    /// Checks that every key of a per-listener `section` names a `listen` entry.
+    fn validate_listener_keys<'a>(
+        &self,
+        section: &str,
+        keys: impl Iterator<Item = &'a String>,
+    ) -> Result<(), ConfigError> {
+        for key in keys {
+            let error =
+                |message: String| ConfigError::new(&format!("{}.{:?}", section, key), message);
+            let listen = key.parse::<ListenAddress>().map_err(error)?;
+            if !self.listen.contains(&listen) {
+                return Err(error("does not match any listen address".to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    fn tls_mut(&mut self) -> &mut TlsConfig {
+        self.tls.get_or_insert_with(|| TlsConfig {
+            certificate: PathBuf::new(),
+            private_key: PathBuf::new(),
+        })
+    }
+}
+
+/// The entry of a per-listener section whose key is `listen`.
+fn for_listener<'a, T>(section: &'a BTreeMap<String, T>, listen: &ListenAddress) -> Option<&'a T> {
+    section
+        .iter()
+        .find(|(key, _)| key.parse::<ListenAddress>().as_ref() == Ok(listen))
+        .map(|(_, value)| value)
+}
+
+/// Parses an environment variable into one of the kebab-case enum variants used in the file.
+fn parse_variant<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    let deserializer: serde::de::value::StrDeserializer<serde::de::value::Error> =
+        value.into_deserializer();
+    T::deserialize(deserializer).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_full_config() {
+        let config = Config::parse(
+            r#"
+            listen = ["0.0.0.0:3478", "[::]:3478/udp", "0.0.0.0:5349/tls"]
+            software = "example"
+
+            [tls]
+            certificate = "cert.pem"
+            private_key = "key.pem"
+
+            [logging]
+            level = "debug"
+            format = "json"
+            packet_dump_every = 100
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen.len(), 3);
+        assert_eq!(config.listen[2].server_type, StunServerEnum::TlsStunServer);
+        assert_eq!(config.software, "example");
+        assert_eq!(config.logging.level, LogLevel::Debug);
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.logging.packet_dump_every, 100);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let error = Config::parse("[logging]\nlevel = \"loud\"\n").unwrap_err();
+        assert_eq!(error.key, "logging.level");
+
+        let error = Config::parse("listen = [\"0.0.0.0:3478/sctp\"]\n").unwrap_err();
+        assert_eq!(error.key, "listen[0]");
+
+        let error = Config::parse("[metrics]\naddress = \"0.0.0.0:9100\"\n").unwrap_err();
+        assert_eq!(error.key, "metrics.address");
+
+        let config = Config::parse("listen = [\"0.0.0.0:5349/tls\"]\n").unwrap();
+        assert_eq!(config.validate().unwrap_err().key, "tls");
+
+        let error =
+            Config::parse("[access.\"0.0.0.0:3478\"]\nallow = [\"10.0.0.1/8\"]\n").unwrap_err();
+        assert_eq!(error.key, "access.0.0.0.0:3478.allow[0]");
+
+        let config = Config::parse("[access.\"0.0.0.0:3478\"]\ndeny = [\"10.0.0.0/8\"]\n").unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().key,
+            "access.\"0.0.0.0:3478\""
+        );
+
+        let config = Config::parse("[auth]\nmode = \"long-term\"\n").unwrap();
+        assert_eq!(config.validate().unwrap_err().key, "auth.users");
+        let config =
+            Config::parse("[auth]\nmode = \"long-term\"\nusers = { alice = \"secret\" }\n")
+                .unwrap();
+        assert_eq!(config.validate().unwrap_err().key, "auth.realm");
+        let config = Config::parse(
+            "[auth]\nmode = \"short-term\"\nusers = { alice = \"secret\" }\npassword_algorithms = [\"sha-256\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        let config = Config::parse(
+            "[auth]\nmode = \"long-term\"\nrealm = \"example.org\"\nusers = { alice = \"secret\" }\nnonce_secrets = [\"new\", \"\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.validate().unwrap_err().key, "auth.nonce_secrets");
+
+        let config = Config::parse("[redirect]\non_shutdown = true\n").unwrap();
+        assert_eq!(config.validate().unwrap_err().key, "redirect.servers");
+        let config = Config::parse(
+            "[redirect]\nshard = true\ntls_servers = [{ address = \"[::1]:5349\", domain = \"\" }]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().key,
+            "redirect.tls_servers[0].domain"
+        );
+    }
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let mut config = Config::parse("software = \"from-file\"\n").unwrap();
+        config
+            .apply_env(vec![
+                ("STUN_SOFTWARE".to_owned(), "from-env".to_owned()),
+                (
+                    "STUN_LISTEN".to_owned(),
+                    "127.0.0.1:3478, [::1]:3478/tcp".to_owned(),
+                ),
+                ("STUN_LOG_FORMAT".to_owned(), "json".to_owned()),
+                ("HOME".to_owned(), "/root".to_owned()),
+            ])
+            .unwrap();
+        assert_eq!(config.software, "from-env");
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.logging.format, LogFormat::Json);
+
+        let error = config
+            .apply_env(vec![("STUN_LOG_LEVEL".to_owned(), "loud".to_owned())])
+            .unwrap_err();
+        assert_eq!(error.key, "STUN_LOG_LEVEL");
+    }
+}
@@ -1,13 +1,25 @@
-use crate::attributes::{Attribute, AttributeEnum, ErrorCode, MappedAddress, XorMappedAddress};
+use crate::attributes::{
+    is_comprehension_required, AlternateDomain, AlternateServer, Attribute, AttributeEnum,
+    AttributeRegistry, ErrorCode, MappedAddress, ResponseOrigin, Software, UnknownAttributes,
+    XorMappedAddress, SOFTWARE,
+};
+use crate::auth::Credentials;
 use crate::errors::ErrorCodeEnum;
-use crate::message::{StunBody, StunHeader, StunMessage, MAGIC_COOKIE};
+use crate::message::{
+    message_type, Class, DecodeError, Method, StunMessage, StunMessageBuilder, StunMessageRef,
+    HEADER_LENGTH, MAGIC_COOKIE,
+};
+use crate::metrics;
+use crate::pipeline::{RequestContext, Transport};
+use crate::redirect::Redirector;
 use byteorder::{BigEndian, ByteOrder};
-use std::net::SocketAddr;
-pub const BINDING_REQUEST: u16 = 0x0001;
-pub const BINDING_RESPONSE: u16 = 0x0101;
-pub const BINDING_ERROR_RESPONSE: u16 = 0x0111;
-pub const BINDING_INDICATION: u16 = 0x0011;
 use std::convert::TryInto;
+use std::net::SocketAddr;
+use tracing::{debug, Span};
+pub const BINDING_REQUEST: u16 = message_type(Method::BINDING, Class::Request);
+pub const BINDING_RESPONSE: u16 = message_type(Method::BINDING, Class::SuccessResponse);
+pub const BINDING_ERROR_RESPONSE: u16 = message_type(Method::BINDING, Class::ErrorResponse);
+pub const BINDING_INDICATION: u16 = message_type(Method::BINDING, Class::Indication);
 
 // pub fn handle_header(stunHeader: &[u8;20]){ //stunMessage skal defineres som struct
 //     let type_ = BigEndian::read_u16(&stunHeader[0..1]);
@@ -19,51 +31,245 @@ use std::convert::TryInto;
 //     return header_type;
 // }
 
-pub fn handle_message(stun_message: &[u8], address: SocketAddr) -> StunMessage {
+/// Builds the response to `stun_message`, received from `address` on `local_address`.
+/// Indications, responses and packets that are not STUN are never answered, so they return
+/// `None`. Responses carry
+/// `software` as their SOFTWARE attribute when it is set. Binding requests `redirect` picks
+/// an alternate for get 300 Try Alternate instead. Requests are not authenticated here;
+/// the server does that in its `Authentication` middleware.
+pub fn handle_message(
+    stun_message: &[u8],
+    address: SocketAddr,
+    local_address: SocketAddr,
+    software: Option<&str>,
+    redirect: Option<&Redirector>,
+) -> Option<StunMessage> {
+    let context = RequestContext {
+        peer: address,
+        local_address,
+        transport: Transport::Udp,
+        tls: None,
+        shutting_down: false,
+    };
+    handle_message_in(
+        Vec::new(),
+        stun_message,
+        &context,
+        software,
+        redirect,
+        &AttributeRegistry::new(),
+        None,
+    )
+}
+
+/// Like `handle_message`, but for a request received as described by `context`, and
+/// encoding a Binding success response into `buffer`, e.g. the bytes of the last response
+/// sent, so that answering it allocates nothing. Comprehension-required attributes in
+/// `attributes` are understood next to the built-in ones. `credentials` are what the
+/// request was authenticated with, if authentication is on.
+pub fn handle_message_in(
+    buffer: Vec<u8>,
+    stun_message: &[u8],
+    context: &RequestContext,
+    software: Option<&str>,
+    redirect: Option<&Redirector>,
+    attributes: &AttributeRegistry,
+    credentials: Option<&Credentials>,
+) -> Option<StunMessage> {
     //let mut response: Vec<u8> = Vec::new();
-    if !check_validity(&stun_message) {
-        return StunMessage {
-            stun_header: StunHeader::new(
-                BINDING_ERROR_RESPONSE,
-                16,
-                stun_message[8..20].try_into().unwrap(),
-            ),
-            stun_body: StunBody {
-                attributes: vec![Box::new(AttributeEnum::ErrorCode({
-                    ErrorCode::new(
-                        ErrorCodeEnum::BadRequest as u32,
-                        ErrorCodeEnum::reason_phrase(&ErrorCodeEnum::BadRequest).to_string(),
-                    )
-                })) as Box<dyn Attribute + Send>],
-            },
-        };
+    if !check_validity(stun_message) {
+        // Not STUN, e.g. another protocol on a multiplexed port: there is no transaction
+        // to answer. 400 is for STUN requests with bad attributes.
+        debug!(
+            length = stun_message.len(),
+            "dropped packet that is not STUN"
+        );
+        metrics::malformed(context.transport.as_str());
+        return None;
+    }
+
+    let type_ = BigEndian::read_u16(&stun_message[0..2]);
+    let method = Method::from_type(type_);
+    match Class::from_type(type_) {
+        Class::Request => {}
+        Class::Indication => {
+            // Nothing to do beyond counting it: indications are mostly NAT keepalives.
+            debug!(%method, "received indication");
+            return None;
+        }
+        _ => {
+            debug!("dropped response sent to the server");
+            return None;
+        }
+    }
+    if method != Method::BINDING {
+        // Other methods are answered by handlers added to `StunServerBuilder`.
+        return Some(error_response(
+            method,
+            stun_message,
+            ErrorCodeEnum::BadRequest,
+            vec![],
+            software,
+        ));
     }
 
-    let stun_body = StunBody {
-        attributes: vec![
-            Box::new(XorMappedAddress::new(
-                address,
-                stun_message[8..20].try_into().unwrap(),
-            )) as Box<dyn Attribute + Send>,
-            Box::new(MappedAddress::new(address)) as Box<dyn Attribute + Send>,
-        ],
+    // Bytes past the length in the header, e.g. the rest of a read buffer, are not part of it.
+    let length = HEADER_LENGTH + BigEndian::read_u16(&stun_message[2..4]) as usize;
+    let decoded = match stun_message.get(..length) {
+        Some(stun_message) => StunMessageRef::parse(stun_message),
+        None => Err(DecodeError::LengthMismatch),
+    };
+    let request = match decoded {
+        Ok(request) => request,
+        Err(e) => {
+            debug!(error = %e, "malformed request");
+            return Some(error_response(
+                Method::BINDING,
+                stun_message,
+                ErrorCodeEnum::BadRequest,
+                vec![],
+                software,
+            ));
+        }
+    };
+    if let Some(attribute) = request.attribute(SOFTWARE) {
+        Span::current().record(
+            "software",
+            String::from_utf8_lossy(attribute.value).as_ref(),
+        );
+    }
+    if let Err(response) = check_comprehension(&request, attributes, software, credentials) {
+        return response;
+    }
+    let protect = |response: StunMessageBuilder| match credentials {
+        Some(credentials) => credentials.protect(response).build(),
+        None => response.build(),
     };
-    let mut body_len: u16 = 0;
+    // Only credentials the Authentication middleware verified count: a 300 must answer a
+    // request with valid MESSAGE-INTEGRITY and be protected with the same key.
+    if let Some(alternate) = redirect.and_then(|redirect| {
+        redirect.redirect(
+            context.peer.ip(),
+            credentials.is_some(),
+            context.shutting_down,
+        )
+    }) {
+        let mut attributes: Vec<Box<dyn Attribute>> =
+            vec![Box::new(AlternateServer::new(alternate.address))];
+        if let Some(domain) = alternate.domain {
+            attributes.push(Box::new(AlternateDomain::new(domain)));
+        }
+        return Some(protect(error_response_builder(
+            Method::BINDING,
+            stun_message,
+            ErrorCodeEnum::TryAlternate,
+            attributes,
+            software,
+        )));
+    }
+
+    let address = context.peer;
+    let transaction_id: [u8; 12] = stun_message[8..20].try_into().unwrap();
+    let mut response = StunMessage::builder_in(
+        buffer,
+        Method::BINDING,
+        Class::SuccessResponse,
+        transaction_id,
+    )
+    .attr(XorMappedAddress::new(address, &transaction_id))
+    .attr(MappedAddress::new(address))
+    .attr(ResponseOrigin::new(context.local_address));
+    if let Some(software) = software {
+        response = response.attr(Software::new(software));
+    }
+    Some(protect(response))
+}
+
+/// The comprehension-required attribute types in `request` the server does not understand,
+/// each listed once. Unknown comprehension-optional attributes are ignored.
+/// Checks `request` for comprehension-required attributes that are neither built in nor in
+/// `attributes`, before any handler sees it. `Err` carries what to answer instead: 420
+/// Unknown Attribute in the request's own method, or nothing for an indication.
+pub(crate) fn check_comprehension(
+    request: &StunMessageRef,
+    attributes: &AttributeRegistry,
+    software: Option<&str>,
+    credentials: Option<&Credentials>,
+) -> Result<(), Option<StunMessage>> {
+    let unknown = unknown_attributes(request, attributes);
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    debug!(
+        ?unknown,
+        "message has unknown comprehension-required attributes"
+    );
+    if request.class() != Class::Request {
+        return Err(None);
+    }
+    let response = error_response_builder(
+        request.method(),
+        request.as_bytes(),
+        ErrorCodeEnum::UnknownAttributes,
+        vec![Box::new(UnknownAttributes::new(unknown))],
+        software,
+    );
+    Err(Some(match credentials {
+        Some(credentials) => credentials.protect(response).build(),
+        None => response.build(),
+    }))
+}
 
-    for attribute in &stun_body.attributes {
-        body_len += &attribute.serialize().len().try_into().unwrap();
+fn unknown_attributes(request: &StunMessageRef, attributes: &AttributeRegistry) -> Vec<u16> {
+    let mut unknown: Vec<u16> = Vec::new();
+    for attribute in request.attributes() {
+        if is_comprehension_required(attribute.type_)
+            && !attributes.is_understood(attribute.type_)
+            && !unknown.contains(&attribute.type_)
+        {
+            unknown.push(attribute.type_);
+        }
     }
+    unknown
+}
 
-    println!("{}", body_len);
+/// An error response of `method` to `stun_message`, carrying ERROR-CODE followed by
+/// `attributes` and SOFTWARE.
+pub(crate) fn error_response(
+    method: Method,
+    stun_message: &[u8],
+    code: ErrorCodeEnum,
+    attributes: Vec<Box<dyn Attribute>>,
+    software: Option<&str>,
+) -> StunMessage {
+    error_response_builder(method, stun_message, code, attributes, software).build()
+}
 
-    return StunMessage {
-        stun_header: StunHeader::new(
-            BINDING_RESPONSE,
-            body_len,
-            stun_message[8..20].try_into().unwrap(),
-        ),
-        stun_body,
-    };
+/// `error_response` before it is built, for more attributes to be added.
+fn error_response_builder(
+    method: Method,
+    stun_message: &[u8],
+    code: ErrorCodeEnum,
+    attributes: Vec<Box<dyn Attribute>>,
+    software: Option<&str>,
+) -> StunMessageBuilder {
+    metrics::error_response(code as u16);
+    let mut response = StunMessage::builder(
+        method,
+        Class::ErrorResponse,
+        stun_message[8..20].try_into().unwrap(),
+    )
+    .attr(AttributeEnum::ErrorCode(ErrorCode::new(
+        code as u32,
+        code.reason_phrase().to_string(),
+    )));
+    for attribute in attributes {
+        response = response.attr(attribute);
+    }
+    if let Some(software) = software {
+        response = response.attr(Software::new(software.to_owned()));
+    }
+    response
 }
 
 //TODO - check length []
@@ -77,18 +283,234 @@ pub fn check_validity(stun_message: &[u8]) -> bool {
     if BigEndian::read_u32(&stun_message[4..8]) != MAGIC_COOKIE {
         return false;
     }
-    let type_ = BigEndian::read_u16(&stun_message[0..2]);
-    if type_ != BINDING_REQUEST && type_ != BINDING_INDICATION {
-        return false;
-    }
-    println!("Message is valid");
-    return true;
+    true
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::attributes::CustomAttribute;
+    use crate::message::{DecodedMessage, StunHeader};
+
+    const TRANSACTION_ID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    fn message(type_: u16) -> Vec<u8> {
+        StunHeader::new(
+            Method::from_type(type_),
+            Class::from_type(type_),
+            0,
+            TRANSACTION_ID,
+        )
+        .serialize()
+    }
+
+    fn binding_request(attributes: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut request = message(BINDING_REQUEST);
+        for (type_, value) in attributes {
+            let mut attribute = vec![0_u8; 4];
+            BigEndian::write_u16(&mut attribute[0..2], *type_);
+            BigEndian::write_u16(&mut attribute[2..4], value.len() as u16);
+            attribute.extend_from_slice(value);
+            attribute.resize((attribute.len() + 3) & !3, 0);
+            request.extend(attribute);
+        }
+        let length = (request.len() - 20) as u16;
+        BigEndian::write_u16(&mut request[2..4], length);
+        request
+    }
+
+    fn handle(type_: u16) -> Option<Vec<u8>> {
+        let address = "192.0.2.1:5000".parse().unwrap();
+        let local_address = "198.51.100.1:3478".parse().unwrap();
+        handle_message(&message(type_), address, local_address, None, None)
+            .map(|response| response.into_bytes())
+    }
+
     #[test]
     fn test() {
         assert_eq!(1, 1);
     }
+
+    #[test]
+    fn test_only_requests_are_answered() {
+        let response = handle(BINDING_REQUEST).unwrap();
+        assert_eq!(BigEndian::read_u16(&response[0..2]), BINDING_RESPONSE);
+        assert_eq!(handle(BINDING_INDICATION), None);
+        assert_eq!(handle(BINDING_RESPONSE), None);
+        assert_eq!(handle(BINDING_ERROR_RESPONSE), None);
+    }
+
+    #[test]
+    fn test_packets_that_are_not_stun_are_dropped() {
+        let address = "192.0.2.1:5000".parse().unwrap();
+        assert_eq!(
+            handle_message(&[0u8; 4], address, address, None, None),
+            None
+        );
+        let mut request = message(BINDING_REQUEST);
+        request[4] ^= 0xFF;
+        assert_eq!(handle_message(&request, address, address, None, None), None);
+    }
+
+    #[test]
+    fn test_unknown_comprehension_required_attributes() {
+        let address = "192.0.2.1:5000".parse().unwrap();
+        let request = |attributes: &[(u16, &[u8])]| {
+            handle_message(&binding_request(attributes), address, address, None, None)
+                .map(|response| response.into_bytes())
+                .unwrap()
+        };
+
+        // CHANGE-REQUEST (RFC 5780) is not supported; an unknown optional attribute is ignored.
+        let response = request(&[(0x0003, &[0, 0, 0, 6]), (0x8050, b"x"), (0x0003, &[0; 4])]);
+        let decoded = DecodedMessage::decode(&response).unwrap();
+        assert_eq!(decoded.type_, BINDING_ERROR_RESPONSE);
+        assert_eq!(
+            decoded
+                .attribute(crate::attributes::ERROR_CODE)
+                .unwrap()
+                .value[2..4],
+            [4, 20]
+        );
+        assert_eq!(
+            decoded
+                .attribute(crate::attributes::UNKNOWN_ATTRIBUTES)
+                .unwrap()
+                .value,
+            vec![0, 3]
+        );
+
+        let response = request(&[(0x8050, b"x"), (crate::attributes::USERNAME, b"user")]);
+        assert_eq!(BigEndian::read_u16(&response[0..2]), BINDING_RESPONSE);
+    }
+
+    #[derive(Debug)]
+    struct Priority(u8);
+
+    impl CustomAttribute for Priority {
+        const TYPE: u16 = 0x7F01;
+        const NAME: &'static str = "PRIORITY";
+
+        fn decode(value: &[u8]) -> Option<Self> {
+            value.first().map(|&priority| Priority(priority))
+        }
+
+        fn value_length(&self) -> u16 {
+            1
+        }
+
+        fn encode_value(&self, value: &mut [u8]) {
+            value[0] = self.0;
+        }
+    }
+
+    #[test]
+    fn test_registered_attributes_are_understood() {
+        let address = "192.0.2.1:5000".parse().unwrap();
+        let context = RequestContext {
+            peer: address,
+            local_address: address,
+            transport: Transport::Udp,
+            tls: None,
+            shutting_down: false,
+        };
+        let request = binding_request(&[(Priority::TYPE, &[7])]);
+        let handle = |attributes: &AttributeRegistry| {
+            handle_message_in(Vec::new(), &request, &context, None, None, attributes, None)
+                .unwrap()
+                .into_bytes()
+        };
+        let mut attributes = AttributeRegistry::new();
+        assert_eq!(
+            BigEndian::read_u16(&handle(&attributes)[0..2]),
+            BINDING_ERROR_RESPONSE
+        );
+
+        attributes.register::<Priority>();
+        assert_eq!(
+            BigEndian::read_u16(&handle(&attributes)[0..2]),
+            BINDING_RESPONSE
+        );
+        let request = StunMessageRef::parse(&request).unwrap();
+        assert_eq!(request.get::<Priority>().unwrap().0, 7);
+    }
+
+    #[test]
+    fn test_software_is_added_to_responses() {
+        let address = "192.0.2.1:5000".parse().unwrap();
+        for type_ in &[BINDING_REQUEST, 0x0003] {
+            let response =
+                handle_message(&message(*type_), address, address, Some("stun/1.0"), None)
+                    .unwrap()
+                    .into_bytes();
+            let decoded = DecodedMessage::decode(&response).unwrap();
+            assert_eq!(decoded.attribute(SOFTWARE).unwrap().value, b"stun/1.0");
+        }
+    }
+
+    #[test]
+    fn test_unknown_methods() {
+        // An Allocate request (TURN) gets 400 in the error class of its own method.
+        let response = handle(0x0003).unwrap();
+        assert_eq!(BigEndian::read_u16(&response[0..2]), 0x0113);
+        let response = handle(0x0009).unwrap();
+        assert_eq!(BigEndian::read_u16(&response[0..2]), 0x0119);
+        assert_eq!(&response[8..20], &TRANSACTION_ID);
+    }
+
+    #[test]
+    fn test_error_responses_are_small() {
+        // Error responses go to unverified sources, so they must not amplify much.
+        let request = message(0x0009);
+        let response = handle(0x0009).unwrap();
+        assert!(response.len() <= 2 * request.len());
+    }
+
+    #[test]
+    fn test_redirects_only_verified_requests_by_default() {
+        let redirector = |unauthenticated| {
+            Redirector::new(&crate::config::RedirectConfig {
+                servers: vec!["198.51.100.2:3478".parse().unwrap()],
+                max_requests_per_second: Some(1),
+                unauthenticated,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let address = "192.0.2.1:5000".parse().unwrap();
+        let handle = |request: &[u8], redirector: &Redirector| {
+            let response = handle_message(request, address, address, None, Some(redirector))
+                .unwrap()
+                .into_bytes();
+            DecodedMessage::decode(&response).unwrap()
+        };
+
+        // Without the Authentication middleware nothing is verified, so nothing is redirected
+        // unless `unauthenticated` allows it, even over the limit.
+        let strict = redirector(false);
+        let request = message(BINDING_REQUEST);
+        assert_eq!(handle(&request, &strict).type_, BINDING_RESPONSE);
+        assert_eq!(handle(&request, &strict).type_, BINDING_RESPONSE);
+
+        let any = redirector(true);
+        assert_eq!(handle(&request, &any).type_, BINDING_RESPONSE);
+        let response = handle(&request, &any);
+        assert_eq!(response.type_, BINDING_ERROR_RESPONSE);
+        assert_eq!(
+            response
+                .attribute(crate::attributes::ERROR_CODE)
+                .unwrap()
+                .value[2..4],
+            [3, 0]
+        );
+        assert_eq!(
+            crate::attributes::decode_address(
+                &response
+                    .attribute(crate::attributes::ALTERNATE_SERVER)
+                    .unwrap()
+                    .value
+            ),
+            Some("198.51.100.2:3478".parse().unwrap())
+        );
+    }
 }
@@ -0,0 +1,219 @@
+//! Liveness and readiness endpoints for orchestrators, served on `health.listen`.
+//!
+//! `/healthz` answers as long as the process runs. `/readyz` answers 200 only while the
+//! listeners are bound, the last Binding request the server sent each of them over loopback
+//! succeeded, and shutdown has not begun. TLS listeners are not probed.
+use crate::client::{binding_request, ClientTransport, ErrorResponse};
+use crate::http::{self, Response};
+use crate::shutdown::{Shutdown, ShutdownSummary};
+use crate::stunserver::{ListenAddress, StunServer, StunServerEnum};
+use async_trait::async_trait;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+const SELF_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct HealthServer {
+    listener: TcpListener,
+    /// Where the self-check Binding requests are sent; empty when no listener can be checked.
+    self_check: Vec<(SocketAddr, ClientTransport)>,
+    self_check_passed: Arc<AtomicBool>,
+}
+
+impl HealthServer {
+    /// Binds the health endpoint. Call once the STUN listeners in `listen` are bound.
+    pub async fn bind(
+        address: SocketAddr,
+        listen: &[ListenAddress],
+    ) -> Result<HealthServer, Box<dyn Error>> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| format!("cannot bind health endpoint {}: {}", address, e))?;
+        let self_check = self_check_targets(listen);
+        Ok(HealthServer {
+            listener,
+            self_check_passed: Arc::new(AtomicBool::new(self_check.is_empty())),
+            self_check,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+#[async_trait]
+impl StunServer for HealthServer {
+    async fn run(&self, shutdown: Shutdown) -> Result<ShutdownSummary, Box<dyn Error>> {
+        let self_check_passed = self.self_check_passed.clone();
+        let shutting_down = shutdown.clone();
+        let route = Arc::new(move |path: &str| match path {
+            "/healthz" => Response::text(200, "ok\n"),
+            "/readyz" if shutting_down.is_shutting_down() => Response::text(503, "shutting down\n"),
+            "/readyz" if !self_check_passed.load(Ordering::SeqCst) => {
+                Response::text(503, "self-check failed\n")
+            }
+            "/readyz" => Response::text(200, "ready\n"),
+            _ => Response::text(404, "not found\n"),
+        });
+
+        let self_check = async {
+            if self.self_check.is_empty() {
+                futures::future::pending().await
+            } else {
+                run_self_check(&self.self_check, &self.self_check_passed).await
+            }
+        };
+        tokio::select! {
+            result = http::serve(&self.listener, route) => result?,
+            _ = self_check => {}
+            _ = shutdown.triggered() => {}
+        }
+        Ok(ShutdownSummary::default())
+    }
+}
+
+/// Probes every target each round; the check passes only when all of them answered.
+async fn run_self_check(targets: &[(SocketAddr, ClientTransport)], passed: &AtomicBool) {
+    loop {
+        let mut failure = None;
+        for &(address, transport) in targets {
+            let result = binding_request(address, transport)
+                .await
+                .map(|_| ())
+                .or_else(|e| {
+                    // With authentication on, a challenge shows the server is answering.
+                    match e.downcast_ref::<ErrorResponse>() {
+                        Some(ErrorResponse { code: Some(401) }) => Ok(()),
+                        _ => Err(e.to_string()),
+                    }
+                });
+            if let Err(e) = result {
+                failure = Some((address, e));
+                break;
+            }
+        }
+        match failure {
+            None => {
+                if !passed.swap(true, Ordering::SeqCst) {
+                    info!("self-check passed, reporting ready");
+                }
+            }
+            Some((address, e)) => {
+                if passed.swap(false, Ordering::SeqCst) {
+                    warn!(%address, error = %e, "self-check failed, reporting not ready");
+                }
+            }
+        }
+        tokio::time::sleep(SELF_CHECK_INTERVAL).await;
+    }
+}
+
+/// The listeners the built-in client can talk to, addressed over loopback.
+fn self_check_targets(listen: &[ListenAddress]) -> Vec<(SocketAddr, ClientTransport)> {
+    listen
+        .iter()
+        .filter_map(|listen| {
+            let transport = match listen.server_type {
+                StunServerEnum::UdpStunServer | StunServerEnum::MultiplexedStunServer => {
+                    ClientTransport::Udp
+                }
+                StunServerEnum::TcpStunServer => ClientTransport::Tcp,
+                StunServerEnum::TlsStunServer => return None,
+            };
+            let ip = match listen.address.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ip => ip,
+            };
+            Some((SocketAddr::new(ip, listen.address.port()), transport))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stunserver::StunServerBuilder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_self_check_targets() {
+        let listen: Vec<ListenAddress> = vec![
+            "0.0.0.0:5349/tls".parse().unwrap(),
+            "[::]:3478/tcp".parse().unwrap(),
+        ];
+        assert_eq!(
+            self_check_targets(&listen),
+            vec![("[::1]:3478".parse().unwrap(), ClientTransport::Tcp)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readiness_follows_self_check_and_shutdown() {
+        // Bound, but nothing answers on it.
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listen = vec![ListenAddress {
+            address: silent.local_addr().unwrap(),
+            server_type: StunServerEnum::UdpStunServer,
+        }];
+        let health = HealthServer::bind("127.0.0.1:0".parse().unwrap(), &listen)
+            .await
+            .unwrap();
+        let health_address = health.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _ = health.run(Shutdown::default()).await;
+        });
+
+        // Nothing answers on the STUN port yet.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(get(health_address, "/healthz")
+            .await
+            .starts_with("HTTP/1.1 200"));
+        assert!(get(health_address, "/readyz")
+            .await
+            .starts_with("HTTP/1.1 503"));
+
+        let config = crate::config::Config {
+            listen: vec!["127.0.0.1:0/udp".parse().unwrap()],
+            ..Default::default()
+        };
+        let server = StunServerBuilder::new().spawn(&config).await.unwrap();
+        let health = HealthServer::bind("127.0.0.1:0".parse().unwrap(), server.local_addresses())
+            .await
+            .unwrap();
+        let health_address = health.local_addr().unwrap();
+        let shutdown = Shutdown::default();
+        let health_shutdown = shutdown.child();
+        tokio::spawn(async move {
+            let _ = health.run(health_shutdown).await;
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(get(health_address, "/readyz")
+            .await
+            .starts_with("HTTP/1.1 200"));
+
+        shutdown.begin();
+        assert!(get(health_address, "/readyz")
+            .await
+            .starts_with("HTTP/1.1 503"));
+        assert!(get(health_address, "/healthz")
+            .await
+            .starts_with("HTTP/1.1 200"));
+    }
+}
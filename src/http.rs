@@ -0,0 +1,126 @@
+//! A minimal HTTP/1.1 responder for the operational endpoints (`/metrics`, `/healthz`, `/readyz`).
+//!
+//! Only `GET` is supported and every connection is closed after one response; that is all
+//! scrapers and probes need, and it keeps a web framework out of the dependency tree.
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn text(status: u16, body: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", body.to_owned())
+    }
+}
+
+/// Maps a request path to a response.
+pub type Route = dyn Fn(&str) -> Response + Send + Sync;
+
+/// Accepts connections on `listener`, answering each with `route`. Accept errors, e.g. running
+/// out of file descriptors, are logged: they must not stop the STUN listeners served alongside.
+pub async fn serve(listener: &TcpListener, route: Arc<Route>) -> Result<(), Box<dyn Error>> {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(error = %e, "HTTP accept failed");
+                continue;
+            }
+        };
+        let route = route.clone();
+        tokio::spawn(async move {
+            if let Err(e) = timeout(REQUEST_TIMEOUT, handle_connection(stream, &*route)).await {
+                debug!(%peer, error = %e, "HTTP request timed out");
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, route: &Route) {
+    let mut request = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(length) => request.extend_from_slice(&buffer[..length]),
+        }
+        if request.len() > MAX_REQUEST_LENGTH {
+            break;
+        }
+    }
+
+    let response = match parse_request_line(&request) {
+        Some(("GET", path)) => route(path),
+        Some(_) => Response::text(405, "method not allowed\n"),
+        None => Response::text(400, "bad request\n"),
+    };
+    let _ = stream.write_all(&encode(&response)).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Returns the method and path (without query string) of the request line.
+fn parse_request_line(request: &[u8]) -> Option<(&str, &str)> {
+    let line = request.split(|&byte| byte == b'\r').next()?;
+    let mut parts = std::str::from_utf8(line).ok()?.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    parts
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))?;
+    Some((method, target.split('?').next().unwrap_or(target)))
+}
+
+fn encode(response: &Response) -> Vec<u8> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_line() {
+        assert_eq!(
+            parse_request_line(b"GET /metrics?x=1 HTTP/1.1\r\nHost: a\r\n\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(parse_request_line(b"GET /metrics\r\n\r\n"), None);
+        assert_eq!(parse_request_line(b"\xff\xfe HTTP/1.1\r\n\r\n"), None);
+    }
+}
@@ -1,5 +1,23 @@
+pub mod access;
 pub mod attributes;
+pub mod auth;
+#[cfg(all(feature = "batched-io", target_os = "linux"))]
+pub mod batch;
+pub mod client;
+pub mod config;
 pub mod errors;
 pub mod handlers;
+pub mod health;
+pub mod http;
+pub mod logging;
 pub mod message;
+pub mod metrics;
+pub mod pipeline;
+pub mod proxy;
+pub mod ratelimit;
+pub mod redirect;
+pub mod shutdown;
 pub mod stunserver;
+pub mod tls;
+#[cfg(target_os = "linux")]
+pub mod udp;
@@ -0,0 +1,127 @@
+//! Log output and per-transaction spans.
+use crate::config::{LogFormat, LogLevel, LoggingConfig};
+use crate::message::{Class, Method, HEADER_LENGTH};
+use byteorder::{BigEndian, ByteOrder};
+use std::error::Error;
+use std::fmt;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use tracing::{debug, info_span, Level, Span};
+
+/// Dump one in this many packets; 0 disables dumps.
+static PACKET_DUMP_EVERY: AtomicU32 = AtomicU32::new(0);
+static PACKETS_SEEN: AtomicU64 = AtomicU64::new(0);
+
+/// Installs the global subscriber. Fails if one is already installed.
+pub fn init(config: &LoggingConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    PACKET_DUMP_EVERY.store(config.packet_dump_every, Ordering::Relaxed);
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level(config.level))
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    }
+}
+
+fn level(level: LogLevel) -> Level {
+    match level {
+        LogLevel::Error => Level::ERROR,
+        LogLevel::Warn => Level::WARN,
+        LogLevel::Info => Level::INFO,
+        LogLevel::Debug => Level::DEBUG,
+        LogLevel::Trace => Level::TRACE,
+    }
+}
+
+/// Span covering one request and its response. The handler records the request's SOFTWARE
+/// attribute in the `software` field.
+pub fn transaction_span(transport: &'static str, peer: SocketAddr, message: &[u8]) -> Span {
+    // Both fields are only formatted when the span is recorded.
+    let (method, transaction_id) = if message.len() >= HEADER_LENGTH {
+        (
+            Some(MessageType(BigEndian::read_u16(&message[0..2]))),
+            Hex(&message[8..20]),
+        )
+    } else {
+        (None, Hex(&[]))
+    };
+    info_span!(
+        "transaction",
+        %peer,
+        transport,
+        method = %MaybeMalformed(method),
+        transaction_id = %transaction_id,
+        software = tracing::field::Empty
+    )
+}
+
+/// Logs `packet` as hex at debug level, for a sample of packets only.
+pub fn dump_packet(direction: &'static str, packet: &[u8]) {
+    if !tracing::enabled!(Level::DEBUG) {
+        return;
+    }
+    let every = PACKET_DUMP_EVERY.load(Ordering::Relaxed);
+    if every == 0
+        || !PACKETS_SEEN
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(u64::from(every))
+    {
+        return;
+    }
+    debug!(direction, packet = %Hex(packet), "packet dump");
+}
+
+/// A message type written as its method and class, e.g. "Allocate Success Response".
+/// Methods without a registered name are written as their number.
+pub struct MessageType(pub u16);
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            Method::from_type(self.0),
+            Class::from_type(self.0)
+        )
+    }
+}
+
+struct MaybeMalformed(Option<MessageType>);
+
+impl fmt::Display for MaybeMalformed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(type_) => type_.fmt(f),
+            None => f.write_str("malformed"),
+        }
+    }
+}
+
+/// Bytes written as lowercase hex when displayed.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    Hex(bytes).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_type_names() {
+        assert_eq!(MessageType(0x0001).to_string(), "Binding Request");
+        assert_eq!(MessageType(0x0103).to_string(), "Allocate Success Response");
+        assert_eq!(MessageType(0x0810).to_string(), "0x200 Indication");
+        assert_eq!(to_hex(&[0x00, 0x0a, 0xff]), "000aff");
+    }
+}
@@ -1,16 +1,11 @@
 extern crate tokio;
 
+mod cli;
+
 use std::env;
-use std::error::Error;
-use stun::stunserver::{parse_program_arguments, StunServerBuilder};
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let server_args = parse_program_arguments(env::args().collect());
-
-    let server = StunServerBuilder::build(server_args.0, server_args.1).await?;
-
-    server.run().await?;
-
-    Ok(())
+async fn main() -> ExitCode {
+    cli::run(env::args().collect()).await
 }
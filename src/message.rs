@@ -1,60 +1,588 @@
 use crate::attributes::*;
+use crate::auth::Integrity;
 use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
 
 pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_LENGTH: usize = 20;
+/// XOR-ed with the CRC-32 of a message to give its FINGERPRINT.
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+/// The method of a message, bits M0 to M11 of its type. Methods not registered here can be
+/// made with `Method::new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Method(u16);
+
+impl Method {
+    pub const BINDING: Method = Method(0x001);
+    // TURN (RFC 8656) and TURN TCP allocations (RFC 6062).
+    pub const ALLOCATE: Method = Method(0x003);
+    pub const REFRESH: Method = Method(0x004);
+    pub const SEND: Method = Method(0x006);
+    pub const DATA: Method = Method(0x007);
+    pub const CREATE_PERMISSION: Method = Method(0x008);
+    pub const CHANNEL_BIND: Method = Method(0x009);
+    pub const CONNECT: Method = Method(0x00A);
+    pub const CONNECTION_BIND: Method = Method(0x00B);
+    pub const CONNECTION_ATTEMPT: Method = Method(0x00C);
+
+    const REGISTERED: &'static [(Method, &'static str)] = &[
+        (Method::BINDING, "Binding"),
+        (Method::ALLOCATE, "Allocate"),
+        (Method::REFRESH, "Refresh"),
+        (Method::SEND, "Send"),
+        (Method::DATA, "Data"),
+        (Method::CREATE_PERMISSION, "CreatePermission"),
+        (Method::CHANNEL_BIND, "ChannelBind"),
+        (Method::CONNECT, "Connect"),
+        (Method::CONNECTION_BIND, "ConnectionBind"),
+        (Method::CONNECTION_ATTEMPT, "ConnectionAttempt"),
+    ];
+
+    /// The method numbered `number`; `None` past the 12 bits a method has.
+    pub const fn new(number: u16) -> Option<Method> {
+        if number > 0x0FFF {
+            None
+        } else {
+            Some(Method(number))
+        }
+    }
+
+    pub const fn number(self) -> u16 {
+        self.0
+    }
+
+    /// The method of a message type, with the class bits taken out (RFC 5389 section 6).
+    pub const fn from_type(type_: u16) -> Method {
+        Method((type_ & 0x000F) | ((type_ >> 1) & 0x0070) | ((type_ >> 2) & 0x0F80))
+    }
+
+    /// The registered name, `None` for methods registered after this was written.
+    pub fn name(self) -> Option<&'static str> {
+        Method::REGISTERED
+            .iter()
+            .find(|(method, _)| *method == self)
+            .map(|(_, name)| *name)
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{:#05x}", self.0),
+        }
+    }
+}
+
+/// The class of a message, bits C0 and C1 of its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+
+impl Class {
+    const fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0x0000,
+            Class::Indication => 0x0010,
+            Class::SuccessResponse => 0x0100,
+            Class::ErrorResponse => 0x0110,
+        }
+    }
+
+    pub const fn from_type(type_: u16) -> Class {
+        match type_ & 0x0110 {
+            0x0000 => Class::Request,
+            0x0010 => Class::Indication,
+            0x0100 => Class::SuccessResponse,
+            _ => Class::ErrorResponse,
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Class::Request => "Request",
+            Class::Indication => "Indication",
+            Class::SuccessResponse => "Success Response",
+            Class::ErrorResponse => "Error Response",
+        })
+    }
+}
+
+/// The message type of `method` in `class`, with the class bits interleaved with the method
+/// bits (RFC 5389 section 6).
+pub const fn message_type(method: Method, class: Class) -> u16 {
+    let method = method.0;
+    (method & 0x000F) | ((method & 0x0070) << 1) | ((method & 0x0F80) << 2) | class.bits()
+}
 
 pub struct StunHeader {
-    type_: u16,
+    method: Method,
+    class: Class,
     length: u16,
     transaction_id: [u8; 12],
 }
 
 impl StunHeader {
     pub fn serialize(&self) -> Vec<u8> {
-        let mut stun_header: Vec<u8> = vec![0 as u8; 8];
-
-        BigEndian::write_u16(&mut stun_header[0..2], self.type_);
-        BigEndian::write_u16(&mut stun_header[2..4], self.length);
-        BigEndian::write_u32(&mut stun_header[4..8], MAGIC_COOKIE);
-        stun_header.append(&mut self.transaction_id.to_vec());
+        let mut stun_header = vec![0_u8; HEADER_LENGTH];
+        self.encode(&mut stun_header);
+        stun_header
+    }
 
-        return stun_header;
+    /// Writes the header to `buffer`, which is `HEADER_LENGTH` bytes long.
+    pub fn encode(&self, buffer: &mut [u8]) {
+        BigEndian::write_u16(&mut buffer[0..2], message_type(self.method, self.class));
+        BigEndian::write_u16(&mut buffer[2..4], self.length);
+        BigEndian::write_u32(&mut buffer[4..8], MAGIC_COOKIE);
+        buffer[8..20].copy_from_slice(&self.transaction_id);
     }
 
-    pub fn new(type_: u16, length: u16, transaction_id: [u8; 12]) -> Self {
+    pub fn new(method: Method, class: Class, length: u16, transaction_id: [u8; 12]) -> Self {
         StunHeader {
-            type_: type_,
-            length: length,
-            transaction_id: transaction_id,
+            method,
+            class,
+            length,
+            transaction_id,
         }
     }
 }
 
-pub struct StunBody {
-    pub attributes: Vec<Box<dyn Attribute>>,
+/// An encoded STUN message, ready to be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StunMessage {
+    bytes: Vec<u8>,
 }
 
-impl StunBody {
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut vec: Vec<u8> = Vec::new();
-        for attribute in &self.attributes {
-            vec.append(&mut attribute.serialize());
+impl StunMessage {
+    /// Starts a message of `method` in `class`.
+    pub fn builder(method: Method, class: Class, transaction_id: [u8; 12]) -> StunMessageBuilder {
+        StunMessage::builder_in(Vec::new(), method, class, transaction_id)
+    }
+
+    /// Like `builder`, but encodes into `buffer`, e.g. the bytes of a message already sent.
+    pub fn builder_in(
+        mut buffer: Vec<u8>,
+        method: Method,
+        class: Class,
+        transaction_id: [u8; 12],
+    ) -> StunMessageBuilder {
+        buffer.clear();
+        StunMessageBuilder::new(buffer, method, class, transaction_id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Reopens the message to append attributes, e.g. MESSAGE-INTEGRITY. It must not end
+    /// with FINGERPRINT yet.
+    pub fn into_builder(self) -> StunMessageBuilder {
+        StunMessageBuilder {
+            buffer: self.bytes,
+            start: 0,
+            integrity: None,
+            fingerprint: false,
+            overflowed: false,
         }
-        return vec;
     }
 }
 
-pub struct StunMessage {
-    pub stun_header: StunHeader,
-    pub stun_body: StunBody,
+/// Memory a message is encoded into, after whatever it already holds.
+pub trait EncodeBuffer {
+    /// Everything written so far.
+    fn written(&mut self) -> &mut [u8];
+
+    /// Appends `length` bytes and returns them, or `None` when they do not fit.
+    fn extend(&mut self, length: usize) -> Option<&mut [u8]>;
 }
 
-impl StunMessage {
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut vec: Vec<u8> = Vec::new();
-        vec.append(&mut self.stun_header.serialize());
-        vec.append(&mut self.stun_body.serialize());
-        return vec;
+impl EncodeBuffer for Vec<u8> {
+    fn written(&mut self) -> &mut [u8] {
+        self
+    }
+
+    fn extend(&mut self, length: usize) -> Option<&mut [u8]> {
+        let start = self.len();
+        self.resize(start + length, 0);
+        Some(&mut self[start..])
+    }
+}
+
+impl EncodeBuffer for BytesMut {
+    fn written(&mut self) -> &mut [u8] {
+        self
+    }
+
+    fn extend(&mut self, length: usize) -> Option<&mut [u8]> {
+        let start = self.len();
+        self.resize(start + length, 0);
+        Some(&mut self[start..])
+    }
+}
+
+/// A fixed `&mut [u8]`, filled from its start.
+pub struct SliceBuffer<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> SliceBuffer<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        SliceBuffer { buffer, length: 0 }
+    }
+
+    /// The number of bytes written.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl EncodeBuffer for SliceBuffer<'_> {
+    fn written(&mut self) -> &mut [u8] {
+        &mut self.buffer[..self.length]
+    }
+
+    fn extend(&mut self, length: usize) -> Option<&mut [u8]> {
+        let start = self.length;
+        let extended = self.buffer.get_mut(start..start + length)?;
+        self.length += length;
+        Some(extended)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    BufferTooSmall,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::BufferTooSmall => write!(f, "message does not fit in the buffer"),
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
+/// Encodes a message attribute by attribute, straight into its buffer. MESSAGE-INTEGRITY and
+/// FINGERPRINT are added by `build`, after every other attribute and in that order, whenever
+/// they were asked for.
+pub struct StunMessageBuilder<B: EncodeBuffer = Vec<u8>> {
+    buffer: B,
+    /// Where the message starts in `buffer`.
+    start: usize,
+    integrity: Option<(Integrity, Vec<u8>)>,
+    fingerprint: bool,
+    overflowed: bool,
+}
+
+impl<B: EncodeBuffer> StunMessageBuilder<B> {
+    /// Starts a message of `method` in `class` at the end of `buffer`.
+    pub fn new(mut buffer: B, method: Method, class: Class, transaction_id: [u8; 12]) -> Self {
+        let start = buffer.written().len();
+        let overflowed = match buffer.extend(HEADER_LENGTH) {
+            Some(header) => {
+                StunHeader::new(method, class, 0, transaction_id).encode(header);
+                false
+            }
+            None => true,
+        };
+        StunMessageBuilder {
+            buffer,
+            start,
+            integrity: None,
+            fingerprint: false,
+            overflowed,
+        }
+    }
+
+    pub fn attr(mut self, attribute: impl Attribute) -> Self {
+        if self.overflowed {
+            return self;
+        }
+        match self.buffer.extend(attribute.encoded_length()) {
+            Some(space) => attribute.encode(space),
+            None => self.overflowed = true,
+        }
+        self
+    }
+
+    /// Protects the message with MESSAGE-INTEGRITY computed with `key`.
+    pub fn integrity(self, key: &[u8]) -> Self {
+        self.integrity_with(Integrity::Sha1, key)
+    }
+
+    /// Protects the message with the given integrity attribute computed with `key`.
+    pub fn integrity_with(mut self, integrity: Integrity, key: &[u8]) -> Self {
+        self.integrity = Some((integrity, key.to_vec()));
+        self
+    }
+
+    pub fn fingerprint(mut self) -> Self {
+        self.fingerprint = true;
+        self
+    }
+
+    /// Adds the last attributes and returns the buffer with the message at its end.
+    pub fn finish(mut self) -> Result<B, EncodeError> {
+        // Each of the last attributes covers the message up to itself, with a header length
+        // that already counts it.
+        if let Some((integrity, key)) = self.integrity.take() {
+            if !self.overflowed {
+                self.set_length(4 + integrity.length());
+                let hmac = integrity.hmac(&key, self.message());
+                self = self.attr(MessageIntegrity::new(integrity.attribute_type(), hmac));
+            }
+        }
+        if self.fingerprint && !self.overflowed {
+            self.set_length(8);
+            let crc = crc32(self.message()) ^ FINGERPRINT_XOR;
+            self = self.attr(Fingerprint::new(crc));
+        }
+        if self.overflowed {
+            return Err(EncodeError::BufferTooSmall);
+        }
+        self.set_length(0);
+        Ok(self.buffer)
+    }
+
+    fn message(&mut self) -> &mut [u8] {
+        let start = self.start;
+        &mut self.buffer.written()[start..]
+    }
+
+    /// Writes the length of the attributes so far plus `more` into the header.
+    fn set_length(&mut self, more: usize) {
+        let message = self.message();
+        let length = message.len() - HEADER_LENGTH + more;
+        BigEndian::write_u16(&mut message[2..4], length as u16);
+    }
+}
+
+impl StunMessageBuilder<Vec<u8>> {
+    pub fn build(self) -> StunMessage {
+        let bytes = self.finish().expect("a vector always has room");
+        StunMessage { bytes }
+    }
+}
+
+/// CRC-32 as used by FINGERPRINT (ISO HDLC, reflected, polynomial 0xEDB88320).
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0_u32, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & 0_u32.wrapping_sub(crc & 1))
+        })
+    })
+}
+
+/// A STUN message read from the wire. Attribute values are kept as raw bytes and decoded
+/// on demand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedMessage {
+    pub type_: u16,
+    pub length: u16,
+    pub transaction_id: [u8; 12],
+    pub attributes: Vec<RawAttribute>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawAttribute {
+    pub type_: u16,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    TooShort,
+    NotStun,
+    BadMagicCookie,
+    LengthMismatch,
+    TruncatedAttribute,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            DecodeError::TooShort => "message is shorter than the 20 byte header",
+            DecodeError::NotStun => "the two most significant bits are not zero",
+            DecodeError::BadMagicCookie => "magic cookie does not match",
+            DecodeError::LengthMismatch => "header length does not match the message size",
+            DecodeError::TruncatedAttribute => "attribute runs past the end of the message",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl Error for DecodeError {}
+
+impl DecodedMessage {
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let message = StunMessageRef::parse(bytes)?;
+        Ok(DecodedMessage {
+            type_: message.type_(),
+            length: message.length(),
+            transaction_id: *message.transaction_id(),
+            attributes: message
+                .attributes()
+                .map(|attribute| RawAttribute {
+                    type_: attribute.type_,
+                    value: attribute.value.to_vec(),
+                })
+                .collect(),
+        })
+    }
+
+    pub fn method(&self) -> Method {
+        Method::from_type(self.type_)
+    }
+
+    pub fn class(&self) -> Class {
+        Class::from_type(self.type_)
+    }
+
+    /// The first attribute of the given type, if present.
+    pub fn attribute(&self, type_: u16) -> Option<&RawAttribute> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.type_ == type_)
+    }
+
+    /// The first `A` attribute, `None` when it is missing or malformed.
+    pub fn get<A: CustomAttribute>(&self) -> Option<A> {
+        A::decode(&self.attribute(A::TYPE)?.value)
+    }
+}
+
+/// A STUN message borrowed from the bytes it was read from. Nothing is copied: attributes are
+/// slices of those bytes, found by iterating over them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StunMessageRef<'a> {
+    bytes: &'a [u8],
+}
+
+/// An attribute of a `StunMessageRef`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttributeRef<'a> {
+    pub type_: u16,
+    pub value: &'a [u8],
+    /// Where the attribute starts in the message.
+    pub offset: usize,
+}
+
+impl<'a> StunMessageRef<'a> {
+    /// Checks the header and that the attributes fill the message exactly.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(DecodeError::TooShort);
+        }
+        if bytes[0] >= 64 {
+            return Err(DecodeError::NotStun);
+        }
+        if BigEndian::read_u32(&bytes[4..8]) != MAGIC_COOKIE {
+            return Err(DecodeError::BadMagicCookie);
+        }
+        let length = BigEndian::read_u16(&bytes[2..4]);
+        if length % 4 != 0 || bytes.len() != HEADER_LENGTH + length as usize {
+            return Err(DecodeError::LengthMismatch);
+        }
+
+        let mut body = &bytes[HEADER_LENGTH..];
+        while !body.is_empty() {
+            if body.len() < 4 {
+                return Err(DecodeError::TruncatedAttribute);
+            }
+            let value_length = BigEndian::read_u16(&body[2..4]) as usize;
+            let padded_length = (value_length + 3) & !3;
+            if body.len() < 4 + padded_length {
+                return Err(DecodeError::TruncatedAttribute);
+            }
+            body = &body[4 + padded_length..];
+        }
+        Ok(StunMessageRef { bytes })
+    }
+
+    pub fn type_(&self) -> u16 {
+        BigEndian::read_u16(&self.bytes[0..2])
+    }
+
+    pub fn method(&self) -> Method {
+        Method::from_type(self.type_())
+    }
+
+    pub fn class(&self) -> Class {
+        Class::from_type(self.type_())
+    }
+
+    pub fn length(&self) -> u16 {
+        BigEndian::read_u16(&self.bytes[2..4])
+    }
+
+    pub fn transaction_id(&self) -> &'a [u8; 12] {
+        self.bytes[8..20].try_into().unwrap()
+    }
+
+    pub fn attributes(&self) -> Attributes<'a> {
+        Attributes {
+            bytes: self.bytes,
+            offset: HEADER_LENGTH,
+        }
+    }
+
+    /// The first attribute of the given type, if present.
+    pub fn attribute(&self, type_: u16) -> Option<AttributeRef<'a>> {
+        self.attributes().find(|attribute| attribute.type_ == type_)
+    }
+
+    /// The first `A` attribute, `None` when it is missing or malformed.
+    pub fn get<A: CustomAttribute>(&self) -> Option<A> {
+        A::decode(self.attribute(A::TYPE)?.value)
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+/// Iterates over the attributes of a `StunMessageRef` in order.
+#[derive(Debug, Clone)]
+pub struct Attributes<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = AttributeRef<'a>;
+
+    fn next(&mut self) -> Option<AttributeRef<'a>> {
+        // `parse` checked that the attributes fit.
+        let header = self.bytes.get(self.offset..self.offset + 4)?;
+        let value_length = BigEndian::read_u16(&header[2..4]) as usize;
+        let attribute = AttributeRef {
+            type_: BigEndian::read_u16(&header[0..2]),
+            value: &self.bytes[self.offset + 4..self.offset + 4 + value_length],
+            offset: self.offset,
+        };
+        self.offset += 4 + ((value_length + 3) & !3);
+        Some(attribute)
     }
 }
 
@@ -67,8 +595,170 @@ impl StunMessage {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test() {
         assert_eq!(1, 1);
     }
+
+    #[test]
+    fn test_decode_binding_response() {
+        let response: [u8; 44] = [
+            1, 1, 0, 24, 33, 18, 164, 66, 28, 54, 51, 69, 185, 194, 210, 171, 179, 118, 57, 253, 0,
+            32, 0, 8, 0, 1, 58, 43, 94, 18, 164, 67, 0, 1, 0, 8, 0, 1, 27, 57, 127, 0, 0, 1,
+        ];
+        let message = DecodedMessage::decode(&response).unwrap();
+        assert_eq!(message.type_, 0x0101);
+        assert_eq!(message.attributes.len(), 2);
+        assert_eq!(
+            decode_xor_address(
+                &message.attribute(XOR_MAPPED_ADDRESS).unwrap().value,
+                &message.transaction_id
+            ),
+            Some("127.0.0.1:6969".parse().unwrap())
+        );
+
+        assert_eq!(
+            DecodedMessage::decode(&response[..40]),
+            Err(DecodeError::LengthMismatch)
+        );
+    }
+
+    #[test]
+    fn test_method_and_class() {
+        let classes = [
+            Class::Request,
+            Class::Indication,
+            Class::SuccessResponse,
+            Class::ErrorResponse,
+        ];
+        // Every method and class survives the interleaving, custom methods included.
+        for number in 0..=0x0FFF {
+            let method = Method::new(number).unwrap();
+            for class in &classes {
+                let type_ = message_type(method, *class);
+                assert!(type_ < 0x4000);
+                assert_eq!(Method::from_type(type_), method);
+                assert_eq!(Class::from_type(type_), *class);
+            }
+        }
+        assert_eq!(
+            message_type(Method::BINDING, Class::SuccessResponse),
+            0x0101
+        );
+        assert_eq!(message_type(Method::ALLOCATE, Class::ErrorResponse), 0x0113);
+        assert_eq!(message_type(Method::DATA, Class::Indication), 0x0017);
+        assert_eq!(
+            message_type(Method::new(0x0FFF).unwrap(), Class::Request),
+            0x3EEF
+        );
+        assert_eq!(Method::new(0x1000), None);
+        assert_eq!(Method::CREATE_PERMISSION.to_string(), "CreatePermission");
+        assert_eq!(Method::new(0x0100).unwrap().to_string(), "0x100");
+    }
+
+    #[test]
+    fn test_builder() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let transaction_id = [7; 12];
+        let message = StunMessage::builder(Method::BINDING, Class::SuccessResponse, transaction_id)
+            .fingerprint()
+            .integrity(b"key")
+            .attr(Software::new("stun".to_owned()))
+            .build();
+        let bytes = message.as_bytes();
+        let decoded = DecodedMessage::decode(bytes).unwrap();
+        assert_eq!(decoded.type_, 0x0101);
+        assert_eq!(decoded.length as usize, bytes.len() - HEADER_LENGTH);
+        let types: Vec<u16> = decoded.attributes.iter().map(|a| a.type_).collect();
+        assert_eq!(types, vec![SOFTWARE, MESSAGE_INTEGRITY, FINGERPRINT]);
+
+        // MESSAGE-INTEGRITY covers the message up to it, with a length ending after it.
+        let mut protected = bytes[..bytes.len() - 32].to_vec();
+        let length = bytes.len() - HEADER_LENGTH - 8;
+        BigEndian::write_u16(&mut protected[2..4], length as u16);
+        assert_eq!(
+            decoded.attribute(MESSAGE_INTEGRITY).unwrap().value,
+            Integrity::Sha1.hmac(b"key", &protected)
+        );
+        let crc = crc32(&bytes[..bytes.len() - 8]) ^ FINGERPRINT_XOR;
+        assert_eq!(
+            decoded.attribute(FINGERPRINT).unwrap().value,
+            crc.to_be_bytes()
+        );
+
+        // The buffer of a sent message is reused.
+        let buffer = message.into_bytes();
+        let capacity = buffer.capacity();
+        let message =
+            StunMessage::builder_in(buffer, Method::BINDING, Class::Request, transaction_id)
+                .build();
+        assert_eq!(message.as_bytes().len(), HEADER_LENGTH);
+        assert_eq!(message.into_bytes().capacity(), capacity);
+    }
+
+    #[test]
+    fn test_message_ref_and_fixed_buffers() {
+        let mut bytes = BytesMut::from(&b"tcp framing"[..]);
+        bytes = StunMessageBuilder::new(bytes, Method::ALLOCATE, Class::Request, [3; 12])
+            .attr(Software::new("stun"))
+            .attr(UnknownAttributes::new(vec![0x0003]))
+            .fingerprint()
+            .finish()
+            .unwrap();
+        let message = StunMessageRef::parse(&bytes[11..]).unwrap();
+        assert_eq!(message.method(), Method::ALLOCATE);
+        assert_eq!(message.transaction_id(), &[3; 12]);
+        let attributes: Vec<(u16, usize)> = message
+            .attributes()
+            .map(|attribute| (attribute.type_, attribute.offset))
+            .collect();
+        assert_eq!(
+            attributes,
+            vec![(SOFTWARE, 20), (UNKNOWN_ATTRIBUTES, 28), (FINGERPRINT, 36)]
+        );
+        assert_eq!(message.attribute(SOFTWARE).unwrap().value, b"stun");
+        assert_eq!(
+            DecodedMessage::decode(&bytes[11..])
+                .unwrap()
+                .attributes
+                .len(),
+            3
+        );
+
+        // A fixed buffer is filled from its start, and must fit the whole message. Padding
+        // is zeroed even over old bytes.
+        let mut fixed = [0xFF_u8; 44];
+        let written = StunMessageBuilder::new(
+            SliceBuffer::new(&mut fixed),
+            Method::ALLOCATE,
+            Class::Request,
+            [3; 12],
+        )
+        .attr(Software::new("stun"))
+        .attr(UnknownAttributes::new(vec![0x0003]))
+        .fingerprint()
+        .finish()
+        .unwrap()
+        .len();
+        assert_eq!(&fixed[..written], &bytes[11..]);
+        let mut short = [0_u8; 40];
+        let result = StunMessageBuilder::new(
+            SliceBuffer::new(&mut short),
+            Method::ALLOCATE,
+            Class::Request,
+            [3; 12],
+        )
+        .attr(Software::new("stun"))
+        .attr(UnknownAttributes::new(vec![0x0003]))
+        .fingerprint()
+        .finish();
+        assert_eq!(result.err(), Some(EncodeError::BufferTooSmall));
+        assert_eq!(
+            StunMessageRef::parse(&bytes[11..bytes.len() - 4]),
+            Err(DecodeError::LengthMismatch)
+        );
+    }
 }
@@ -0,0 +1,290 @@
+//! Prometheus metrics, served on `metrics.listen` at `/metrics`.
+//!
+//! The recording functions are always available so call sites need no `cfg`; without the
+//! `metrics` feature they compile to nothing.
+use crate::message::{Class, Method};
+use std::time::Duration;
+
+/// Records a request that reached the message handler.
+pub fn request(message_type: u16, transport: &'static str) {
+    #[cfg(feature = "metrics")]
+    registry::get()
+        .requests
+        .with_label_values(&[
+            method_name(message_type),
+            class_name(message_type),
+            transport,
+        ])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (message_type, transport);
+}
+
+/// Records an error response sent with `code`.
+pub fn error_response(code: u16) {
+    #[cfg(feature = "metrics")]
+    registry::get()
+        .error_responses
+        .with_label_values(&[&code.to_string()])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = code;
+}
+
+/// Records a packet that was dropped because it could not be parsed.
+pub fn malformed(transport: &'static str) {
+    #[cfg(feature = "metrics")]
+    registry::get()
+        .malformed
+        .with_label_values(&[transport])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = transport;
+}
+
+/// Records a packet, connection or error response dropped by the rate limiter. `scope` is
+/// the limit that was hit: `ip`, `prefix`, `error-response-prefix` or `error-response`.
+pub fn rate_limited(scope: &'static str) {
+    #[cfg(feature = "metrics")]
+    registry::get()
+        .rate_limited
+        .with_label_values(&[scope])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = scope;
+}
+
+/// Records a 401 or 438 challenge dropped by the error response budget.
+pub fn challenge_dropped() {
+    #[cfg(feature = "metrics")]
+    registry::get().challenges_dropped.inc();
+}
+
+/// Records how long handling one request took.
+pub fn latency(transport: &'static str, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    registry::get()
+        .latency
+        .with_label_values(&[transport])
+        .observe(elapsed.as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = (transport, elapsed);
+}
+
+/// Counts an open TCP or TLS connection until the returned guard is dropped.
+pub fn connection(transport: &'static str) -> ConnectionGuard {
+    #[cfg(feature = "metrics")]
+    registry::get()
+        .connections
+        .with_label_values(&[transport])
+        .inc();
+    ConnectionGuard { transport }
+}
+
+pub struct ConnectionGuard {
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    transport: &'static str,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        registry::get()
+            .connections
+            .with_label_values(&[self.transport])
+            .dec();
+    }
+}
+
+/// The method part of a message type, as a label with bounded cardinality.
+pub fn method_name(message_type: u16) -> &'static str {
+    match Method::from_type(message_type) {
+        Method::BINDING => "binding",
+        _ => "other",
+    }
+}
+
+pub fn class_name(message_type: u16) -> &'static str {
+    match Class::from_type(message_type) {
+        Class::Request => "request",
+        Class::Indication => "indication",
+        Class::SuccessResponse => "success-response",
+        Class::ErrorResponse => "error-response",
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub use registry::MetricsServer;
+
+#[cfg(feature = "metrics")]
+mod registry {
+    use crate::http::{self, Response};
+    use crate::shutdown::{Shutdown, ShutdownSummary};
+    use crate::stunserver::StunServer;
+    use async_trait::async_trait;
+    use prometheus::{
+        Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+        Opts, Registry, TextEncoder,
+    };
+    use std::error::Error;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, OnceLock};
+    use tokio::net::TcpListener;
+
+    pub(super) struct Metrics {
+        registry: Registry,
+        pub(super) requests: IntCounterVec,
+        pub(super) error_responses: IntCounterVec,
+        pub(super) malformed: IntCounterVec,
+        pub(super) rate_limited: IntCounterVec,
+        pub(super) challenges_dropped: IntCounter,
+        pub(super) latency: HistogramVec,
+        pub(super) connections: IntGaugeVec,
+        // TURN is not implemented; these stay at zero until it is.
+        _turn_allocations: IntGauge,
+        _turn_relayed_bytes: IntCounter,
+    }
+
+    pub(super) fn get() -> &'static Metrics {
+        static METRICS: OnceLock<Metrics> = OnceLock::new();
+        METRICS.get_or_init(|| Metrics::new().expect("metric definitions are valid"))
+    }
+
+    impl Metrics {
+        fn new() -> prometheus::Result<Metrics> {
+            let registry = Registry::new_custom(Some("stun".to_owned()), None)?;
+            let requests = IntCounterVec::new(
+                Opts::new("requests_total", "STUN messages handled"),
+                &["method", "class", "transport"],
+            )?;
+            let error_responses = IntCounterVec::new(
+                Opts::new(
+                    "error_responses_total",
+                    "Error responses sent, by error code",
+                ),
+                &["code"],
+            )?;
+            let malformed = IntCounterVec::new(
+                Opts::new("malformed_packets_total", "Packets dropped as unparseable"),
+                &["transport"],
+            )?;
+            let rate_limited = IntCounterVec::new(
+                Opts::new("rate_limited_total", "Traffic dropped by the rate limiter"),
+                &["scope"],
+            )?;
+            let challenges_dropped = IntCounter::new(
+                "challenges_dropped_total",
+                "401 and 438 challenges dropped by the error response budget",
+            )?;
+            let latency = HistogramVec::new(
+                HistogramOpts::new(
+                    "request_duration_seconds",
+                    "Time spent handling one request",
+                )
+                .buckets(prometheus::exponential_buckets(0.000_005, 2.0, 16)?),
+                &["transport"],
+            )?;
+            let connections = IntGaugeVec::new(
+                Opts::new("active_connections", "Open TCP and TLS connections"),
+                &["transport"],
+            )?;
+            let turn_allocations = IntGauge::new("turn_allocations", "Active TURN allocations")?;
+            let turn_relayed_bytes =
+                IntCounter::new("turn_relayed_bytes_total", "Bytes relayed through TURN")?;
+
+            registry.register(Box::new(requests.clone()))?;
+            registry.register(Box::new(error_responses.clone()))?;
+            registry.register(Box::new(malformed.clone()))?;
+            registry.register(Box::new(rate_limited.clone()))?;
+            registry.register(Box::new(challenges_dropped.clone()))?;
+            registry.register(Box::new(latency.clone()))?;
+            registry.register(Box::new(connections.clone()))?;
+            registry.register(Box::new(turn_allocations.clone()))?;
+            registry.register(Box::new(turn_relayed_bytes.clone()))?;
+            Ok(Metrics {
+                registry,
+                requests,
+                error_responses,
+                malformed,
+                rate_limited,
+                challenges_dropped,
+                latency,
+                connections,
+                _turn_allocations: turn_allocations,
+                _turn_relayed_bytes: turn_relayed_bytes,
+            })
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render() -> String {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+        encoder
+            .encode(&get().registry.gather(), &mut buffer)
+            .expect("text encoding does not fail");
+        String::from_utf8(buffer).expect("text encoding is UTF-8")
+    }
+
+    /// Serves `/metrics` over HTTP.
+    pub struct MetricsServer {
+        listener: TcpListener,
+    }
+
+    impl MetricsServer {
+        pub async fn bind(address: SocketAddr) -> Result<MetricsServer, Box<dyn Error>> {
+            let listener = TcpListener::bind(address)
+                .await
+                .map_err(|e| format!("cannot bind metrics endpoint {}: {}", address, e))?;
+            Ok(MetricsServer { listener })
+        }
+
+        pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.listener.local_addr()
+        }
+    }
+
+    #[async_trait]
+    impl StunServer for MetricsServer {
+        async fn run(&self, shutdown: Shutdown) -> Result<ShutdownSummary, Box<dyn Error>> {
+            let route = Arc::new(|path: &str| match path {
+                "/metrics" => Response::new(200, prometheus::TEXT_FORMAT, render()),
+                _ => Response::text(404, "not found\n"),
+            });
+            tokio::select! {
+                result = http::serve(&self.listener, route) => result?,
+                _ = shutdown.triggered() => {}
+            }
+            Ok(ShutdownSummary::default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_and_class_names() {
+        assert_eq!(
+            (method_name(0x0001), class_name(0x0001)),
+            ("binding", "request")
+        );
+        assert_eq!(class_name(0x0011), "indication");
+        assert_eq!(class_name(0x0101), "success-response");
+        assert_eq!(class_name(0x0111), "error-response");
+        assert_eq!(method_name(0x0003), "other");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_render_includes_recorded_metrics() {
+        request(0x0001, "udp");
+        error_response(400);
+        let text = registry::render();
+        assert!(text.contains(
+            "stun_requests_total{class=\"request\",method=\"binding\",transport=\"udp\"}"
+        ));
+        assert!(text.contains("stun_error_responses_total{code=\"400\"}"));
+    }
+}
@@ -0,0 +1,656 @@
+use crate::attributes::{
+    AttributeRegistry, ERROR_CODE, FINGERPRINT, MESSAGE_INTEGRITY, MESSAGE_INTEGRITY_SHA256,
+};
+use crate::auth::{Authenticator, Credentials};
+use crate::handlers::{check_comprehension, check_validity, error_response, handle_message_in};
+use crate::logging::{dump_packet, transaction_span};
+use crate::message::{
+    Class, DecodeError, Method, StunMessage, StunMessageBuilder, StunMessageRef, HEADER_LENGTH,
+};
+use crate::metrics;
+use crate::ratelimit::RateLimiter;
+use crate::redirect::Redirector;
+use async_trait::async_trait;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, Instrument};
+
+/// How a request reached the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+impl Transport {
+    /// The name used in logs and metrics labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+        }
+    }
+}
+
+/// What was negotiated on a TLS connection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsInfo {
+    /// The server name the client asked for (SNI), if any.
+    pub server_name: Option<String>,
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// E.g. `TLSv1_3`.
+    pub protocol_version: Option<String>,
+}
+
+/// Where a request came from and how. For connections from a trusted proxy the addresses
+/// are those in the PROXY header.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub peer: SocketAddr,
+    pub local_address: SocketAddr,
+    pub transport: Transport,
+    /// Set for requests received over TLS.
+    pub tls: Option<Arc<TlsInfo>>,
+    /// Whether the server's shutdown had begun when the request arrived.
+    pub shutting_down: bool,
+}
+
+/// A request on its way through the pipeline. `bytes` holds at least a STUN header.
+pub struct Request<'a> {
+    pub bytes: &'a [u8],
+    pub context: &'a RequestContext,
+    /// What the request was authenticated with, set by `Authentication`.
+    pub credentials: Option<&'a Credentials>,
+    /// Memory the response can be encoded into, see `response`.
+    pub buffer: Vec<u8>,
+}
+
+impl<'a> Request<'a> {
+    pub fn method(&self) -> Method {
+        Method::from_type(BigEndian::read_u16(&self.bytes[0..2]))
+    }
+
+    pub fn class(&self) -> Class {
+        Class::from_type(BigEndian::read_u16(&self.bytes[0..2]))
+    }
+
+    pub fn transaction_id(&self) -> [u8; 12] {
+        self.bytes[8..20].try_into().unwrap()
+    }
+
+    /// Parses the request. Bytes past the length in its header, e.g. the rest of a read
+    /// buffer, are not part of it.
+    pub fn message(&self) -> Result<StunMessageRef<'a>, DecodeError> {
+        let length = HEADER_LENGTH + BigEndian::read_u16(&self.bytes[2..4]) as usize;
+        match self.bytes.get(..length) {
+            Some(message) => StunMessageRef::parse(message),
+            None => Err(DecodeError::LengthMismatch),
+        }
+    }
+
+    /// Starts a response of `class` to the request, encoded into `buffer`.
+    pub fn response(&mut self, class: Class) -> StunMessageBuilder {
+        StunMessage::builder_in(
+            mem::take(&mut self.buffer),
+            self.method(),
+            class,
+            self.transaction_id(),
+        )
+    }
+}
+
+/// Answers the requests and indications of a method.
+#[async_trait]
+pub trait StunHandler: Send + Sync {
+    /// The response to `request`, or `None` to send nothing back.
+    async fn handle(&self, request: Request<'_>) -> Option<StunMessage>;
+}
+
+/// Wraps the handlers: it may answer a request itself, pass it on with `next`, or change the
+/// response that comes back.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle(&self, request: Request<'_>, next: Next<'_>) -> Option<StunMessage>;
+}
+
+/// The rest of the pipeline after a middleware.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    handler: &'a (dyn StunHandler + 'a),
+}
+
+impl<'a> Next<'a> {
+    pub async fn run(self, request: Request<'_>) -> Option<StunMessage> {
+        match self.middleware.split_first() {
+            Some((first, middleware)) => {
+                let next = Next {
+                    middleware,
+                    handler: self.handler,
+                };
+                first.handle(request, next).await
+            }
+            None => self.handler.handle(request).await,
+        }
+    }
+}
+
+/// Middleware in order, then the handler registered for the method of the request. Methods
+/// without one get the built-in handling: Binding, else 400 Bad Request.
+pub struct Pipeline {
+    middleware: Vec<Arc<dyn Middleware>>,
+    router: Router,
+}
+
+impl Pipeline {
+    /// The built-in handling answers Binding requests with `software`, `redirect` and the
+    /// custom `attributes` like `handle_message_in` does.
+    pub fn new(
+        middleware: Vec<Arc<dyn Middleware>>,
+        handlers: BTreeMap<Method, Arc<dyn StunHandler>>,
+        software: Option<Arc<str>>,
+        redirect: Option<Arc<Redirector>>,
+        attributes: Arc<AttributeRegistry>,
+    ) -> Pipeline {
+        Pipeline {
+            middleware,
+            router: Router {
+                handlers,
+                software,
+                redirect,
+                attributes,
+            },
+        }
+    }
+
+    /// Runs a received packet through the pipeline. Packets too short to carry a STUN
+    /// header cannot be answered and are dropped.
+    pub async fn handle(
+        &self,
+        bytes: &[u8],
+        context: &RequestContext,
+        buffer: Vec<u8>,
+    ) -> Option<StunMessage> {
+        if bytes.len() < HEADER_LENGTH {
+            debug!(
+                peer = %context.peer,
+                length = bytes.len(),
+                "dropped packet shorter than a STUN header"
+            );
+            metrics::malformed(context.transport.as_str());
+            return None;
+        }
+        let request = Request {
+            bytes,
+            context,
+            credentials: None,
+            buffer,
+        };
+        let next = Next {
+            middleware: &self.middleware,
+            handler: &self.router,
+        };
+        next.run(request).await
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("middleware", &self.middleware.len())
+            .field("handlers", &self.router.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+struct Router {
+    handlers: BTreeMap<Method, Arc<dyn StunHandler>>,
+    software: Option<Arc<str>>,
+    redirect: Option<Arc<Redirector>>,
+    attributes: Arc<AttributeRegistry>,
+}
+
+#[async_trait]
+impl StunHandler for Router {
+    async fn handle(&self, request: Request<'_>) -> Option<StunMessage> {
+        if check_validity(request.bytes)
+            && matches!(request.class(), Class::Request | Class::Indication)
+        {
+            if let Some(handler) = self.handlers.get(&request.method()) {
+                if let Ok(message) = request.message() {
+                    if let Err(response) = check_comprehension(
+                        &message,
+                        &self.attributes,
+                        self.software.as_deref(),
+                        request.credentials,
+                    ) {
+                        return response;
+                    }
+                }
+                return handler.handle(request).await;
+            }
+        }
+        handle_message_in(
+            request.buffer,
+            request.bytes,
+            request.context,
+            self.software.as_deref(),
+            self.redirect.as_deref(),
+            &self.attributes,
+            request.credentials,
+        )
+    }
+}
+
+/// Logs each transaction in a span, with packet dumps at debug level.
+pub struct Logging;
+
+#[async_trait]
+impl Middleware for Logging {
+    async fn handle(&self, request: Request<'_>, next: Next<'_>) -> Option<StunMessage> {
+        let context = request.context;
+        let span = transaction_span(context.transport.as_str(), context.peer, request.bytes);
+        async move {
+            dump_packet("request", request.bytes);
+            let response = next.run(request).await;
+            match &response {
+                Some(response) => {
+                    dump_packet("response", response.as_bytes());
+                    debug!(
+                        response_length = response.as_bytes().len(),
+                        "handled request"
+                    );
+                }
+                None => debug!("handled message without a response"),
+            }
+            response
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Counts requests by type and transport and records how long they took.
+pub struct Metrics;
+
+#[async_trait]
+impl Middleware for Metrics {
+    async fn handle(&self, request: Request<'_>, next: Next<'_>) -> Option<StunMessage> {
+        let transport = request.context.transport.as_str();
+        let started = Instant::now();
+        metrics::request(BigEndian::read_u16(&request.bytes[0..2]), transport);
+        let response = next.run(request).await;
+        metrics::latency(transport, started.elapsed());
+        response
+    }
+}
+
+/// Drops error responses to UDP sources, which may be spoofed, once the budget of their
+/// prefix or the global budget of the limiter is used up. Dropped 401 and 438 challenges
+/// are counted, since legitimate clients cannot authenticate without them. Sources
+/// themselves are admitted by the listener, before their datagrams or connections are read.
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>) -> RateLimit {
+        RateLimit { limiter }
+    }
+}
+
+#[async_trait]
+impl Middleware for RateLimit {
+    async fn handle(&self, request: Request<'_>, next: Next<'_>) -> Option<StunMessage> {
+        let transport = request.context.transport;
+        let peer = request.context.peer.ip();
+        next.run(request).await.filter(|response| {
+            if transport != Transport::Udp
+                || Class::from_type(BigEndian::read_u16(&response.as_bytes()[0..2]))
+                    != Class::ErrorResponse
+                || self.limiter.admit_error_response(peer)
+            {
+                return true;
+            }
+            if is_challenge(response) {
+                metrics::challenge_dropped();
+            }
+            false
+        })
+    }
+}
+
+/// Requires requests to be authenticated. The rest of the pipeline sees the credentials in
+/// `Request::credentials`, and responses are protected with them unless they already end
+/// with an integrity or fingerprint attribute.
+pub struct Authentication {
+    auth: Arc<Authenticator>,
+    software: Option<Arc<str>>,
+}
+
+impl Authentication {
+    /// Rejections carry `software` as their SOFTWARE attribute when it is set.
+    pub fn new(auth: Arc<Authenticator>, software: Option<Arc<str>>) -> Authentication {
+        Authentication { auth, software }
+    }
+}
+
+#[async_trait]
+impl Middleware for Authentication {
+    async fn handle(&self, request: Request<'_>, next: Next<'_>) -> Option<StunMessage> {
+        // Indications cannot be challenged; what is not STUN is dropped further on, and malformed
+        // requests get 400.
+        let message = match request.message() {
+            Ok(message) if request.class() == Class::Request && check_validity(request.bytes) => {
+                message
+            }
+            _ => return next.run(request).await,
+        };
+        let credentials = match self.auth.authenticate(&message, request.context.peer.ip()) {
+            Ok(credentials) => credentials,
+            Err(rejection) => {
+                debug!(code = rejection.code as u16, "request not authenticated");
+                return Some(error_response(
+                    request.method(),
+                    request.bytes,
+                    rejection.code,
+                    rejection.attributes,
+                    self.software.as_deref(),
+                ));
+            }
+        };
+        let response = next
+            .run(Request {
+                credentials: Some(&credentials),
+                ..request
+            })
+            .await?;
+        let finished = StunMessageRef::parse(response.as_bytes()).map_or(true, |response| {
+            response.attributes().any(|attribute| {
+                matches!(
+                    attribute.type_,
+                    MESSAGE_INTEGRITY | MESSAGE_INTEGRITY_SHA256 | FINGERPRINT
+                )
+            })
+        });
+        if finished {
+            return Some(response);
+        }
+        Some(credentials.protect(response.into_builder()).build())
+    }
+}
+
+/// Whether `response` is a 401 or 438 challenge for the client's credentials.
+fn is_challenge(response: &StunMessage) -> bool {
+    StunMessageRef::parse(response.as_bytes())
+        .ok()
+        .and_then(|response| response.attribute(ERROR_CODE))
+        .is_some_and(|error_code| {
+            matches!(error_code.value.get(2..4), Some([4, 1]) | Some([4, 38]))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attributes::{Custom, CustomAttribute, Software, Text, ERROR_CODE, SOFTWARE};
+    use crate::config::{AuthConfig, AuthMode};
+    use crate::message::{StunHeader, MAGIC_COOKIE};
+    use std::sync::Mutex;
+
+    const PING: Method = match Method::new(0x100) {
+        Some(method) => method,
+        None => panic!(),
+    };
+
+    struct Ping;
+
+    #[async_trait]
+    impl StunHandler for Ping {
+        async fn handle(&self, mut request: Request<'_>) -> Option<StunMessage> {
+            let transport = request.context.transport.as_str();
+            Some(
+                request
+                    .response(Class::SuccessResponse)
+                    .attr(Software::new(transport))
+                    .build(),
+            )
+        }
+    }
+
+    /// Records the method of each request it sees.
+    struct Record(Arc<Mutex<Vec<Method>>>);
+
+    #[async_trait]
+    impl Middleware for Record {
+        async fn handle(&self, request: Request<'_>, next: Next<'_>) -> Option<StunMessage> {
+            self.0.lock().unwrap().push(request.method());
+            next.run(request).await
+        }
+    }
+
+    fn request(method: Method) -> Vec<u8> {
+        let mut request = StunHeader::new(method, Class::Request, 0, [7; 12]).serialize();
+        BigEndian::write_u32(&mut request[4..8], MAGIC_COOKIE);
+        request
+    }
+
+    fn context(transport: Transport) -> RequestContext {
+        RequestContext {
+            peer: "192.0.2.1:5000".parse().unwrap(),
+            local_address: "198.51.100.1:3478".parse().unwrap(),
+            transport,
+            tls: None,
+            shutting_down: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handlers_and_middleware() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers: BTreeMap<Method, Arc<dyn StunHandler>> = BTreeMap::new();
+        handlers.insert(PING, Arc::new(Ping));
+        let pipeline = Pipeline::new(
+            vec![Arc::new(Logging), Arc::new(Record(seen.clone()))],
+            handlers,
+            None,
+            None,
+            Arc::default(),
+        );
+        let context = context(Transport::Tcp);
+
+        let response = pipeline
+            .handle(&request(PING), &context, Vec::new())
+            .await
+            .unwrap();
+        let response = StunMessageRef::parse(response.as_bytes()).unwrap();
+        assert_eq!(response.method(), PING);
+        assert_eq!(response.class(), Class::SuccessResponse);
+        assert_eq!(response.attribute(SOFTWARE).unwrap().value, b"tcp");
+
+        let response = pipeline
+            .handle(&request(Method::BINDING), &context, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            StunMessageRef::parse(response.as_bytes()).unwrap().class(),
+            Class::SuccessResponse
+        );
+        assert_eq!(pipeline.handle(&[0; 8], &context, Vec::new()).await, None);
+        assert_eq!(*seen.lock().unwrap(), vec![PING, Method::BINDING]);
+    }
+
+    fn authentication() -> Arc<dyn Middleware> {
+        let auth = Authenticator::new(&AuthConfig {
+            mode: AuthMode::ShortTerm,
+            users: vec![("alice".to_owned(), "secret".to_owned())]
+                .into_iter()
+                .collect(),
+            ..AuthConfig::default()
+        })
+        .unwrap();
+        Arc::new(Authentication::new(Arc::new(auth), None))
+    }
+
+    /// The error code of `response`, e.g. `(4, 20)` for 420.
+    fn error_code(response: &StunMessage) -> (u8, u8) {
+        let response = StunMessageRef::parse(response.as_bytes()).unwrap();
+        let value = response.attribute(ERROR_CODE).unwrap().value;
+        (value[2], value[3])
+    }
+
+    #[tokio::test]
+    async fn test_authentication_covers_custom_methods() {
+        let mut handlers: BTreeMap<Method, Arc<dyn StunHandler>> = BTreeMap::new();
+        handlers.insert(PING, Arc::new(Ping));
+        let pipeline = Pipeline::new(vec![authentication()], handlers, None, None, Arc::default());
+        let context = context(Transport::Udp);
+
+        let response = pipeline
+            .handle(&request(PING), &context, Vec::new())
+            .await
+            .unwrap();
+        let response = StunMessageRef::parse(response.as_bytes()).unwrap();
+        assert_eq!(response.class(), Class::ErrorResponse);
+        assert_eq!(response.attribute(ERROR_CODE).unwrap().value[2..4], [4, 0]);
+
+        let request = StunMessage::builder(PING, Class::Request, [7; 12])
+            .attr(Text::username("alice".to_owned()))
+            .integrity(b"secret")
+            .build();
+        let response = pipeline
+            .handle(request.as_bytes(), &context, Vec::new())
+            .await
+            .unwrap();
+        let response = StunMessageRef::parse(response.as_bytes()).unwrap();
+        assert_eq!(response.class(), Class::SuccessResponse);
+        assert!(response.attribute(MESSAGE_INTEGRITY).is_some());
+    }
+
+    /// CHANGE-REQUEST (RFC 5780), which the server does not support.
+    #[derive(Debug)]
+    struct ChangeRequest;
+
+    impl CustomAttribute for ChangeRequest {
+        const TYPE: u16 = 0x0003;
+        const NAME: &'static str = "CHANGE-REQUEST";
+
+        fn decode(_: &[u8]) -> Option<Self> {
+            Some(ChangeRequest)
+        }
+
+        fn value_length(&self) -> u16 {
+            4
+        }
+
+        fn encode_value(&self, value: &mut [u8]) {
+            value.fill(0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authentication_precedes_unknown_attributes() {
+        let pipeline = Pipeline::new(
+            vec![authentication()],
+            BTreeMap::new(),
+            None,
+            None,
+            Arc::default(),
+        );
+        let context = context(Transport::Udp);
+        let request = |username: Option<&str>| {
+            let mut request = StunMessage::builder(Method::BINDING, Class::Request, [7; 12])
+                .attr(Custom(ChangeRequest));
+            if let Some(username) = username {
+                request = request
+                    .attr(Text::username(username.to_owned()))
+                    .integrity(b"secret");
+            }
+            request.build()
+        };
+
+        let response = pipeline
+            .handle(request(None).as_bytes(), &context, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(error_code(&response), (4, 0));
+
+        let response = pipeline
+            .handle(request(Some("alice")).as_bytes(), &context, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(error_code(&response), (4, 20));
+        let response = StunMessageRef::parse(response.as_bytes()).unwrap();
+        assert!(response.attribute(MESSAGE_INTEGRITY).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_handlers_only_see_understood_attributes() {
+        let mut handlers: BTreeMap<Method, Arc<dyn StunHandler>> = BTreeMap::new();
+        handlers.insert(PING, Arc::new(Ping));
+        let pipeline = Pipeline::new(Vec::new(), handlers, None, None, Arc::default());
+        let context = context(Transport::Udp);
+        let message = |class| {
+            StunMessage::builder(PING, class, [7; 12])
+                .attr(Custom(ChangeRequest))
+                .build()
+        };
+
+        let response = pipeline
+            .handle(message(Class::Request).as_bytes(), &context, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(error_code(&response), (4, 20));
+        let response = StunMessageRef::parse(response.as_bytes()).unwrap();
+        assert_eq!(response.method(), PING);
+        assert_eq!(response.class(), Class::ErrorResponse);
+
+        let indication = message(Class::Indication);
+        assert_eq!(
+            pipeline
+                .handle(indication.as_bytes(), &context, Vec::new())
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redirects_verified_requests() {
+        let redirector = Redirector::new(&crate::config::RedirectConfig {
+            servers: vec!["198.51.100.2:3478".parse().unwrap()],
+            on_shutdown: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let pipeline = Pipeline::new(
+            vec![authentication()],
+            BTreeMap::new(),
+            None,
+            Some(Arc::new(redirector)),
+            Arc::default(),
+        );
+        let context = RequestContext {
+            shutting_down: true,
+            ..context(Transport::Udp)
+        };
+        let request = StunMessage::builder(Method::BINDING, Class::Request, [7; 12])
+            .attr(Text::username("alice".to_owned()))
+            .integrity(b"secret")
+            .build();
+
+        let response = pipeline
+            .handle(request.as_bytes(), &context, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(error_code(&response), (3, 0));
+        let response = StunMessageRef::parse(response.as_bytes()).unwrap();
+        assert!(response.attribute(MESSAGE_INTEGRITY).is_some());
+    }
+}
@@ -0,0 +1,166 @@
+//! HAProxy PROXY protocol headers (v1 text and v2 binary) sent by load balancers in front of
+//! TCP and TLS listeners.
+//!
+//! The header is read byte for byte as needed, never past its end, so the stream can be handed
+//! to the TLS acceptor or the STUN handler afterwards.
+use byteorder::{BigEndian, ByteOrder};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header allowed by the specification, CRLF included.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LENGTH: usize = 16;
+
+/// The connection as the client made it, before the proxy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Reads the header at the start of `stream`. Returns `None` for headers that carry no
+/// addresses (v1 `UNKNOWN`, v2 `LOCAL` or an unsupported family), which proxies send for
+/// their own health checks.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<ProxyHeader>, Box<dyn Error>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = vec![0_u8; V1_PREFIX.len()];
+    stream.read_exact(&mut header).await?;
+    if header == V1_PREFIX {
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX_LENGTH {
+                return Err("PROXY v1 header is too long".into());
+            }
+            header.push(stream.read_u8().await?);
+        }
+        parse_v1(&header)
+    } else if V2_SIGNATURE.starts_with(&header) {
+        header.resize(V2_HEADER_LENGTH, 0);
+        stream.read_exact(&mut header[V1_PREFIX.len()..]).await?;
+        if !header.starts_with(V2_SIGNATURE) {
+            return Err("invalid PROXY v2 signature".into());
+        }
+        let length = BigEndian::read_u16(&header[14..16]) as usize;
+        header.resize(V2_HEADER_LENGTH + length, 0);
+        stream.read_exact(&mut header[V2_HEADER_LENGTH..]).await?;
+        parse_v2(&header)
+    } else {
+        Err("connection from a trusted proxy does not start with a PROXY header".into())
+    }
+}
+
+/// Parses `PROXY TCP4 192.0.2.1 198.51.100.1 56324 3478\r\n`.
+fn parse_v1(header: &[u8]) -> Result<Option<ProxyHeader>, Box<dyn Error>> {
+    let line = std::str::from_utf8(&header[..header.len() - 2])?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let address = |address: &str, port: &str| -> Result<SocketAddr, Box<dyn Error>> {
+                let address: IpAddr = address.parse()?;
+                if address.is_ipv4() != (*family == "TCP4") {
+                    return Err(format!("{} address in a {} PROXY header", address, family).into());
+                }
+                Ok(SocketAddr::new(address, port.parse()?))
+            };
+            Ok(Some(ProxyHeader {
+                source: address(source, source_port)?,
+                destination: address(destination, destination_port)?,
+            }))
+        }
+        _ => Err(format!("invalid PROXY v1 header {:?}", line).into()),
+    }
+}
+
+fn parse_v2(header: &[u8]) -> Result<Option<ProxyHeader>, Box<dyn Error>> {
+    let version_command = header[12];
+    if version_command >> 4 != 2 {
+        return Err(format!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        )
+        .into());
+    }
+    match version_command & 0x0F {
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(format!("unknown PROXY v2 command {}", command).into()),
+    }
+    let addresses = &header[V2_HEADER_LENGTH..];
+    // The high nibble is the address family, the low nibble the transport protocol.
+    match header[13] >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = |offset: usize| {
+                IpAddr::V4(Ipv4Addr::from(BigEndian::read_u32(&addresses[offset..])))
+            };
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), BigEndian::read_u16(&addresses[8..])),
+                destination: SocketAddr::new(ip(4), BigEndian::read_u16(&addresses[10..])),
+            }))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip = |offset: usize| {
+                IpAddr::V6(Ipv6Addr::from(BigEndian::read_u128(&addresses[offset..])))
+            };
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), BigEndian::read_u16(&addresses[32..])),
+                destination: SocketAddr::new(ip(16), BigEndian::read_u16(&addresses[34..])),
+            }))
+        }
+        1 | 2 => Err("PROXY v2 header is too short for its address family".into()),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_v1_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 3478\r\nSTUN";
+        let header = read_header(&mut stream).await.unwrap().unwrap();
+        assert_eq!(header.source, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(header.destination, "198.51.100.1:3478".parse().unwrap());
+        assert_eq!(stream, b"STUN");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 3478\r\n";
+        let header = read_header(&mut stream).await.unwrap().unwrap();
+        assert_eq!(header.source, "[2001:db8::1]:56324".parse().unwrap());
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+
+        let mut stream: &[u8] = b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+        let mut stream: &[u8] = b"\x00\x01\x00\x00!\x12\xa4B";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_header() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x0D, 0x96]);
+        header.extend_from_slice(b"STUN");
+        let mut stream = header.as_slice();
+        let parsed = read_header(&mut stream).await.unwrap().unwrap();
+        assert_eq!(parsed.source, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(parsed.destination, "198.51.100.1:3478".parse().unwrap());
+        assert_eq!(stream, b"STUN");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend_from_slice(&[0x21, 0x21, 0, 12]);
+        truncated.extend_from_slice(&[0; 12]);
+        assert!(read_header(&mut truncated.as_slice()).await.is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Token-bucket rate limiting per source address and per source prefix.
+//!
+//! Every reply is larger than the request it answers, so an unlimited UDP server can be
+//! used to amplify traffic towards a spoofed source. Sources over their limit are dropped
+//! without a reply. Error responses have a budget per source prefix and a global one.
+use crate::config::RateLimitConfig;
+use crate::metrics;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Error responses sent per second when `rate_limit.error_responses` is unset.
+pub const DEFAULT_ERROR_RESPONSES: u32 = 100;
+
+/// Error responses sent per second to one prefix when
+/// `rate_limit.error_responses_per_prefix` is unset.
+pub const DEFAULT_ERROR_RESPONSES_PER_PREFIX: u32 = 10;
+
+/// Buckets are swept once the table grows past this many sources.
+const MIN_SWEEP_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Limit {
+    rate: f64,
+    capacity: f64,
+}
+
+impl Limit {
+    fn new(rate: u32, burst: Option<u32>) -> Limit {
+        Limit {
+            rate: f64::from(rate),
+            capacity: f64::from(burst.unwrap_or(rate).max(1)),
+        }
+    }
+
+    /// Refills `bucket` up to `now` and takes one token if there is one.
+    fn take(&self, bucket: &mut Bucket, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, bucket: &Bucket, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens + elapsed * self.rate >= self.capacity
+    }
+}
+
+/// Buckets for one kind of key, created on first use and swept when idle.
+#[derive(Debug)]
+struct Buckets {
+    limit: Limit,
+    buckets: Mutex<(HashMap<IpAddr, Bucket>, usize)>,
+}
+
+impl Buckets {
+    fn new(limit: Limit) -> Buckets {
+        Buckets {
+            limit,
+            buckets: Mutex::new((HashMap::new(), MIN_SWEEP_SIZE)),
+        }
+    }
+
+    fn take(&self, key: IpAddr, now: Instant) -> bool {
+        let mut guard = self.buckets.lock().unwrap();
+        let (buckets, sweep_at) = &mut *guard;
+        if buckets.len() >= *sweep_at {
+            // A full bucket behaves exactly like a missing one, so it can be dropped.
+            buckets.retain(|_, bucket| !self.limit.is_full(bucket, now));
+            *sweep_at = (buckets.len() * 2).max(MIN_SWEEP_SIZE);
+        }
+        let capacity = self.limit.capacity;
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        self.limit.take(bucket, now)
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_ip: Option<Buckets>,
+    per_prefix: Option<Buckets>,
+    error_responses_per_prefix: Buckets,
+    error_responses: Mutex<Bucket>,
+    error_limit: Limit,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        let error_rate = config.error_responses.unwrap_or(DEFAULT_ERROR_RESPONSES);
+        RateLimiter {
+            per_ip: config
+                .per_ip
+                .map(|rate| Buckets::new(Limit::new(rate, config.burst))),
+            per_prefix: config
+                .per_prefix
+                .map(|rate| Buckets::new(Limit::new(rate, config.burst))),
+            error_responses_per_prefix: Buckets::new(Limit::new(
+                config
+                    .error_responses_per_prefix
+                    .unwrap_or(DEFAULT_ERROR_RESPONSES_PER_PREFIX),
+                None,
+            )),
+            error_responses: Mutex::new(Bucket {
+                tokens: f64::from(error_rate),
+                updated: Instant::now(),
+            }),
+            error_limit: Limit::new(error_rate, None),
+        }
+    }
+
+    /// Whether a request from `source` may be handled.
+    pub fn admit(&self, source: IpAddr) -> bool {
+        self.admit_at(source, Instant::now())
+    }
+
+    fn admit_at(&self, source: IpAddr, now: Instant) -> bool {
+        let source = canonical(source);
+        if let Some(per_ip) = &self.per_ip {
+            if !per_ip.take(source, now) {
+                metrics::rate_limited("ip");
+                return false;
+            }
+        }
+        if let Some(per_prefix) = &self.per_prefix {
+            if !per_prefix.take(prefix(source), now) {
+                metrics::rate_limited("prefix");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether an error response may be sent to `destination`. Error responses are
+    /// answered to sources that may well be spoofed, so each prefix has a budget of its own,
+    /// which keeps a flood from one network from using up the budget shared by all sources.
+    pub fn admit_error_response(&self, destination: IpAddr) -> bool {
+        self.admit_error_response_at(destination, Instant::now())
+    }
+
+    fn admit_error_response_at(&self, destination: IpAddr, now: Instant) -> bool {
+        if !self
+            .error_responses_per_prefix
+            .take(prefix(canonical(destination)), now)
+        {
+            metrics::rate_limited("error-response-prefix");
+            return false;
+        }
+        let admitted = self
+            .error_limit
+            .take(&mut self.error_responses.lock().unwrap(), now);
+        if !admitted {
+            metrics::rate_limited("error-response");
+        }
+        admitted
+    }
+}
+
+/// Unmaps IPv4-mapped IPv6 addresses, as seen on dual-stack sockets.
+pub(crate) fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => address,
+        },
+        IpAddr::V4(_) => address,
+    }
+}
+
+/// `address` with its IP unmapped like `canonical`, e.g. the source of a request to a
+/// dual-stack listener as reported back to the client.
+pub(crate) fn canonical_socket_addr(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(canonical(address.ip()), address.port())
+}
+
+/// The /24 (IPv4) or /64 (IPv6) network of `address`.
+fn prefix(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & 0xFFFF_FF00)),
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(
+            u128::from(v6) & 0xFFFF_FFFF_FFFF_FFFF_0000_0000_0000_0000,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter(per_ip: Option<u32>, per_prefix: Option<u32>, burst: Option<u32>) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            per_ip,
+            per_prefix,
+            burst,
+            error_responses: Some(1),
+            error_responses_per_prefix: None,
+        })
+    }
+
+    #[test]
+    fn test_per_ip_bucket_refills() {
+        let limiter = limiter(Some(2), None, Some(3));
+        let source: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        assert!((0..3).all(|_| limiter.admit_at(source, now)));
+        assert!(!limiter.admit_at(source, now));
+        assert!(limiter.admit_at("192.0.2.2".parse().unwrap(), now));
+        assert!(limiter.admit_at(source, now + Duration::from_millis(500)));
+        assert!(!limiter.admit_at(source, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_prefix_limit_covers_neighbours() {
+        let limiter = limiter(None, Some(1), None);
+        let now = Instant::now();
+        assert!(limiter.admit_at("2001:db8::1".parse().unwrap(), now));
+        assert!(!limiter.admit_at("2001:db8::ffff".parse().unwrap(), now));
+        assert!(limiter.admit_at("2001:db8:0:1::1".parse().unwrap(), now));
+        assert!(limiter.admit_at("198.51.100.7".parse().unwrap(), now));
+        assert!(!limiter.admit_at("::ffff:198.51.100.8".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn test_error_responses_share_one_budget() {
+        let limiter = limiter(None, None, None);
+        let now = Instant::now();
+        let source = "192.0.2.1".parse().unwrap();
+        assert!(limiter.admit_error_response_at(source, now));
+        assert!(!limiter.admit_error_response_at("198.51.100.1".parse().unwrap(), now));
+        assert!(limiter.admit_error_response_at(source, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_one_prefix_cannot_use_up_the_error_budget() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            error_responses: Some(10),
+            error_responses_per_prefix: Some(2),
+            ..RateLimitConfig::default()
+        });
+        let now = Instant::now();
+        let flooded = (0..100)
+            .filter(|i| limiter.admit_error_response_at(IpAddr::from([192, 0, 2, *i]), now))
+            .count();
+        assert_eq!(flooded, 2);
+        assert!(limiter.admit_error_response_at("198.51.100.1".parse().unwrap(), now));
+    }
+}
@@ -0,0 +1,262 @@
+//! Redirecting clients to other servers with 300 Try Alternate and ALTERNATE-SERVER.
+//!
+//! A client is redirected while the server shuts down, while it gets more Binding requests
+//! per second than configured, or when sharding assigns its address to another server. The
+//! alternate is chosen by a hash of the client address, so a client keeps being sent to the
+//! same server. TLS clients are only sent to TLS servers, along with the ALTERNATE-DOMAIN
+//! their certificate is checked against (RFC 8489 section 10).
+use crate::config::RedirectConfig;
+use crate::ratelimit::canonical;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const LOAD_WINDOW: Duration = Duration::from_secs(1);
+
+/// A server a client is redirected to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alternate {
+    pub address: SocketAddr,
+    /// Name in the server's certificate, for TLS clients.
+    pub domain: Option<String>,
+}
+
+/// The redirection policy as applied by one listener. Listeners share the request count.
+#[derive(Debug, Clone)]
+pub struct Redirector {
+    servers: Arc<Vec<Alternate>>,
+    tls_servers: Arc<Vec<Alternate>>,
+    /// Whether the listener's clients use TLS, and so are sent to `tls_servers`.
+    tls: bool,
+    on_shutdown: bool,
+    max_requests_per_second: Option<u32>,
+    shard: bool,
+    unauthenticated: bool,
+    /// Start of the current one-second window and the requests counted in it.
+    load: Arc<Mutex<(Instant, u32)>>,
+}
+
+impl Redirector {
+    /// The policy for UDP and TCP listeners; `None` when `config` never redirects.
+    pub fn new(config: &RedirectConfig) -> Option<Redirector> {
+        if !config.is_enabled() {
+            return None;
+        }
+        let servers = config
+            .servers
+            .iter()
+            .map(|address| Alternate {
+                address: *address,
+                domain: None,
+            })
+            .collect();
+        let tls_servers = config
+            .tls_servers
+            .iter()
+            .map(|alternate| Alternate {
+                address: alternate.address,
+                domain: Some(alternate.domain.clone()),
+            })
+            .collect();
+        Some(Redirector {
+            servers: Arc::new(servers),
+            tls_servers: Arc::new(tls_servers),
+            tls: false,
+            on_shutdown: config.on_shutdown,
+            max_requests_per_second: config.max_requests_per_second,
+            shard: config.shard,
+            unauthenticated: config.unauthenticated,
+            load: Arc::new(Mutex::new((Instant::now(), 0))),
+        })
+    }
+
+    /// The same policy for a TLS listener, which redirects to the TLS servers.
+    pub fn for_tls(&self) -> Redirector {
+        Redirector {
+            tls: true,
+            ..self.clone()
+        }
+    }
+
+    /// Counts a Binding request from `source` and returns the server it should be sent to
+    /// instead, if any. `authenticated` is whether the Authentication middleware verified the
+    /// request's MESSAGE-INTEGRITY, `shutting_down` whether the server's shutdown has begun.
+    pub fn redirect(
+        &self,
+        source: IpAddr,
+        authenticated: bool,
+        shutting_down: bool,
+    ) -> Option<Alternate> {
+        self.choose(source, authenticated, shutting_down, Instant::now())
+    }
+
+    fn choose(
+        &self,
+        source: IpAddr,
+        authenticated: bool,
+        shutting_down: bool,
+        now: Instant,
+    ) -> Option<Alternate> {
+        let overloaded = self.count_request(now);
+        if !authenticated && !self.unauthenticated {
+            return None;
+        }
+        // ALTERNATE-SERVER must have the address family of the request's source.
+        let source = canonical(source);
+        let servers = if self.tls {
+            &self.tls_servers
+        } else {
+            &self.servers
+        };
+        let candidates: Vec<&Alternate> = servers
+            .iter()
+            .filter(|server| canonical(server.address.ip()).is_ipv4() == source.is_ipv4())
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let hash = hash(source);
+        let (reason, server) = if self.on_shutdown && shutting_down {
+            (
+                "shutting down",
+                candidates[(hash % candidates.len() as u64) as usize],
+            )
+        } else if overloaded {
+            (
+                "overloaded",
+                candidates[(hash % candidates.len() as u64) as usize],
+            )
+        } else if self.shard {
+            // Bucket 0 is this server, the others are the candidates.
+            match (hash % (candidates.len() as u64 + 1)) as usize {
+                0 => return None,
+                bucket => ("sharded", candidates[bucket - 1]),
+            }
+        } else {
+            return None;
+        };
+        debug!(server = %server.address, reason, "redirecting client");
+        Some(server.clone())
+    }
+
+    /// Counts a request at `now` and returns whether the window is over the limit.
+    fn count_request(&self, now: Instant) -> bool {
+        let max = match self.max_requests_per_second {
+            Some(max) => max,
+            None => return false,
+        };
+        let mut load = self.load.lock().unwrap();
+        let (start, count) = &mut *load;
+        if now.saturating_duration_since(*start) >= LOAD_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count = count.saturating_add(1);
+        *count > max
+    }
+}
+
+/// FNV-1a of the address bytes. Unlike `DefaultHasher` it is the same in every process, so
+/// servers sharing a configuration shard clients the same way.
+fn hash(address: IpAddr) -> u64 {
+    let octets = match address {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    };
+    octets.iter().fold(0xcbf2_9ce4_8422_2325, |hash, octet| {
+        (hash ^ u64::from(*octet)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TlsAlternate;
+
+    fn redirector(config: RedirectConfig) -> Redirector {
+        Redirector::new(&RedirectConfig {
+            servers: vec![
+                "198.51.100.2:3478".parse().unwrap(),
+                "198.51.100.3:3478".parse().unwrap(),
+                "[2001:db8::2]:3478".parse().unwrap(),
+            ],
+            tls_servers: vec![TlsAlternate {
+                address: "198.51.100.2:5349".parse().unwrap(),
+                domain: "stun-b.example.org".to_owned(),
+            }],
+            ..config
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_redirects_on_shutdown_within_family_and_transport() {
+        let redirector = redirector(RedirectConfig {
+            on_shutdown: true,
+            ..RedirectConfig::default()
+        });
+        let now = Instant::now();
+        let v4 = "192.0.2.1".parse().unwrap();
+        let v6 = "2001:db8::7".parse().unwrap();
+        assert_eq!(redirector.choose(v4, true, false, now), None);
+        let server = redirector.choose(v4, true, true, now).unwrap();
+        assert!(server.address.is_ipv4());
+        assert_eq!(server.domain, None);
+        assert_eq!(redirector.choose(v4, true, true, now), Some(server));
+        assert_eq!(
+            redirector.choose(v6, true, true, now).unwrap().address,
+            "[2001:db8::2]:3478".parse().unwrap()
+        );
+
+        let tls = redirector.for_tls();
+        assert_eq!(
+            tls.choose(v4, true, true, now),
+            Some(Alternate {
+                address: "198.51.100.2:5349".parse().unwrap(),
+                domain: Some("stun-b.example.org".to_owned()),
+            })
+        );
+        assert_eq!(tls.choose(v6, true, true, now), None);
+        assert!(Redirector::new(&RedirectConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_redirects_when_overloaded() {
+        let redirector = redirector(RedirectConfig {
+            max_requests_per_second: Some(2),
+            ..RedirectConfig::default()
+        });
+        let now = Instant::now();
+        let source = "192.0.2.1".parse().unwrap();
+        assert_eq!(redirector.choose(source, true, false, now), None);
+        assert_eq!(redirector.choose(source, true, false, now), None);
+        // Unauthenticated requests count towards the load but are never redirected.
+        assert_eq!(redirector.choose(source, false, false, now), None);
+        assert!(redirector.choose(source, true, false, now).is_some());
+        assert_eq!(
+            redirector.choose(source, true, false, now + LOAD_WINDOW),
+            None
+        );
+    }
+
+    #[test]
+    fn test_shards_by_source_address() {
+        let redirector = redirector(RedirectConfig {
+            shard: true,
+            ..RedirectConfig::default()
+        });
+        let now = Instant::now();
+        let mut kept = 0;
+        for host in 0..=255 {
+            let source = IpAddr::from([192, 0, 2, host]);
+            let server = redirector.choose(source, true, false, now);
+            assert_eq!(redirector.choose(source, true, false, now), server);
+            if server.is_none() {
+                kept += 1;
+            }
+        }
+        // One share of three stays with this server.
+        assert!((50..120).contains(&kept), "kept {}", kept);
+    }
+}
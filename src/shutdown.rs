@@ -0,0 +1,216 @@
+//! Graceful shutdown shared by every listener of a server.
+//!
+//! Triggering a `Shutdown` makes listeners stop accepting and return. TCP and TLS
+//! connections already accepted get `drain_timeout` to finish their transaction before
+//! they are aborted. TURN is not implemented, so there are no allocations to release.
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::error::Error;
+use std::future::Future;
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A cloneable handle that stops the servers it is passed to.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    token: CancellationToken,
+    /// Set by `begin` or `trigger`, shared with child handles.
+    shutting_down: Arc<AtomicBool>,
+    drain_timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(drain_timeout: Duration) -> Shutdown {
+        Shutdown {
+            token: CancellationToken::new(),
+            shutting_down: Arc::default(),
+            drain_timeout,
+        }
+    }
+
+    /// Announces the shutdown while the listeners keep serving: `/readyz` fails so load
+    /// balancers stop sending new traffic, and `on_shutdown` redirects start.
+    pub fn begin(&self) {
+        if !self.shutting_down.swap(true, Ordering::SeqCst) {
+            info!("shutting down, reporting not ready");
+        }
+    }
+
+    /// Whether `begin` or `trigger` has been called on this handle, its parent or a child.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn trigger(&self) {
+        self.begin();
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves once `trigger` has been called on this handle or its parent.
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// A handle that is triggered with this one, but can also be triggered on its own.
+    pub fn child(&self) -> Shutdown {
+        Shutdown {
+            token: self.token.child_token(),
+            shutting_down: self.shutting_down.clone(),
+            drain_timeout: self.drain_timeout,
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new(DEFAULT_DRAIN_TIMEOUT)
+    }
+}
+
+/// What a server did before it stopped.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ShutdownSummary {
+    /// UDP datagrams answered.
+    pub udp_requests: u64,
+    /// TCP and TLS connections accepted.
+    pub connections: u64,
+    /// Connections still open when the drain timeout ran out.
+    pub aborted_connections: u64,
+}
+
+impl AddAssign for ShutdownSummary {
+    fn add_assign(&mut self, other: ShutdownSummary) {
+        self.udp_requests += other.udp_requests;
+        self.connections += other.connections;
+        self.aborted_connections += other.aborted_connections;
+    }
+}
+
+/// Connection tasks of one listener, so they can be drained on shutdown.
+pub(crate) struct Connections {
+    tracker: TaskTracker,
+    abort: CancellationToken,
+}
+
+impl Connections {
+    pub(crate) fn new() -> Connections {
+        Connections {
+            tracker: TaskTracker::new(),
+            abort: CancellationToken::new(),
+        }
+    }
+
+    /// Spawns a connection task that is cut short if draining times out.
+    pub(crate) fn spawn<F>(&self, connection: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let abort = self.abort.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = connection => {}
+                _ = abort.cancelled() => {}
+            }
+        });
+    }
+
+    /// Waits up to `timeout` for open connections, then aborts the rest. Returns how many
+    /// were aborted.
+    pub(crate) async fn drain(&self, timeout: Duration) -> u64 {
+        self.tracker.close();
+        if tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_ok()
+        {
+            return 0;
+        }
+        let aborted = self.tracker.len() as u64;
+        warn!(aborted, "drain timeout reached, aborting connections");
+        self.abort.cancel();
+        self.tracker.wait().await;
+        aborted
+    }
+}
+
+/// Runs `servers` to completion. The first failure stops the others through a child of
+/// `shutdown`, and is returned once they have all stopped.
+pub(crate) async fn run_all(
+    servers: Vec<BoxFuture<'_, Result<ShutdownSummary, Box<dyn Error>>>>,
+    shutdown: &Shutdown,
+) -> Result<ShutdownSummary, Box<dyn Error>> {
+    let mut running: FuturesUnordered<_> = servers.into_iter().collect();
+    let mut summary = ShutdownSummary::default();
+    // Kept as a string: a `Box<dyn Error>` held across the await would make this future !Send.
+    let mut first_error: Option<String> = None;
+    while let Some(result) = running.next().await {
+        match result {
+            Ok(server_summary) => summary += server_summary,
+            Err(e) => {
+                if first_error.is_none() {
+                    first_error = Some(e.to_string());
+                }
+                shutdown.trigger();
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e.into()),
+        None => Ok(summary),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_aborts_connections_after_timeout() {
+        let connections = Connections::new();
+        connections.spawn(async {});
+        connections.spawn(futures::future::pending());
+        assert_eq!(connections.drain(Duration::from_millis(50)).await, 1);
+    }
+
+    #[test]
+    fn test_begin_is_shared_with_children() {
+        let shutdown = Shutdown::default();
+        let child = shutdown.child();
+        assert!(!child.is_shutting_down());
+        shutdown.begin();
+        assert!(child.is_shutting_down());
+        assert!(!child.token.is_cancelled());
+        assert!(!Shutdown::default().is_shutting_down());
+    }
+
+    #[tokio::test]
+    async fn test_run_all_stops_siblings_on_failure() {
+        let shutdown = Shutdown::default();
+        let child = shutdown.child();
+        let waiting = async {
+            child.triggered().await;
+            Ok(ShutdownSummary {
+                udp_requests: 1,
+                ..ShutdownSummary::default()
+            })
+        };
+        let failing = async { Err::<ShutdownSummary, Box<dyn Error>>("bind failed".into()) };
+        let result = run_all(vec![Box::pin(waiting), Box::pin(failing)], &child).await;
+        assert_eq!(result.unwrap_err().to_string(), "bind failed");
+        assert!(!shutdown.is_triggered());
+    }
+}
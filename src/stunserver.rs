@@ -1,302 +1,1195 @@
 extern crate tokio;
-use crate::handlers::handle_message;
+use crate::access::{AccessList, AccessLists, Cidr};
+use crate::attributes::{AttributeRegistry, CustomAttribute};
+use crate::auth::Authenticator;
+#[cfg(all(feature = "batched-io", target_os = "linux"))]
+use crate::batch::udp_loop;
+use crate::config::{Config, RateLimitConfig};
+use crate::health::HealthServer;
+use crate::message::{Class, Method, HEADER_LENGTH};
+use crate::metrics;
+use crate::pipeline::{
+    Authentication, Logging, Metrics, Middleware, Pipeline, RateLimit, RequestContext, StunHandler,
+    TlsInfo, Transport,
+};
+use crate::proxy;
+use crate::ratelimit::{canonical_socket_addr, RateLimiter};
+use crate::redirect::Redirector;
+use crate::shutdown::{run_all, Connections, Shutdown, ShutdownSummary};
+use crate::tls::load_acceptor;
+#[cfg(target_os = "linux")]
+use crate::udp::{recv_from, send_from};
 use async_trait::async_trait;
+use byteorder::{BigEndian, ByteOrder};
+use socket2::{Domain, Socket, Type};
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::future::Future;
+use std::mem;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+#[cfg(not(all(feature = "batched-io", target_os = "linux")))]
+use udp_loop_unbatched as udp_loop;
 
 #[async_trait]
-pub trait StunServer {
-    async fn run(&self) -> Result<(), Box<dyn Error>>;
+pub trait StunServer: Send + Sync {
+    /// Serves until `shutdown` is triggered or a listener fails.
+    async fn run(&self, shutdown: Shutdown) -> Result<ShutdownSummary, Box<dyn Error>>;
+
+    /// The addresses the STUN listeners are bound to, with port 0 resolved.
+    fn local_addresses(&self) -> Vec<ListenAddress> {
+        Vec::new()
+    }
+
+    /// Counters shared by the listeners, if the server keeps them.
+    fn stats(&self) -> Option<Arc<ServerStats>> {
+        None
+    }
+}
+
+/// Live counters of a server, shared by its listeners.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    requests: AtomicU64,
+    responses: AtomicU64,
+    error_responses: AtomicU64,
+    connections: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            requests: self.requests.load(Ordering::Relaxed),
+            responses: self.responses.load(Ordering::Relaxed),
+            error_responses: self.error_responses.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What a server has done so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Packets long enough to carry a STUN header, over every transport.
+    pub requests: u64,
+    /// Responses sent, error responses included.
+    pub responses: u64,
+    pub error_responses: u64,
+    /// TCP and TLS connections accepted.
+    pub connections: u64,
+}
+
+/// A server running as a task of a tokio runtime.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addresses: Vec<ListenAddress>,
+    stats: Option<Arc<ServerStats>>,
+    shutdown: Shutdown,
+    task: JoinHandle<Result<ShutdownSummary, String>>,
+}
+
+impl ServerHandle {
+    /// Spawns `server` on the current runtime. It runs until `shutdown` is triggered, either
+    /// directly or with `ServerHandle::shutdown`.
+    pub fn spawn(server: Box<dyn StunServer>, shutdown: Shutdown) -> ServerHandle {
+        let local_addresses = server.local_addresses();
+        let stats = server.stats();
+        let task = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { server.run(shutdown).await.map_err(|e| e.to_string()) }
+        });
+        ServerHandle {
+            local_addresses,
+            stats,
+            shutdown,
+            task,
+        }
+    }
+
+    /// The addresses the STUN listeners are bound to, with port 0 resolved.
+    pub fn local_addresses(&self) -> &[ListenAddress] {
+        &self.local_addresses
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+            .as_ref()
+            .map(|stats| stats.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// Marks the server as shutting down without stopping it, so that `on_shutdown`
+    /// redirects start. `shutdown` does this too.
+    pub fn begin_shutdown(&self) {
+        self.shutdown.begin();
+    }
+
+    /// Stops accepting and starts draining connections; `join` waits until that is done.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Waits for the server to stop.
+    pub async fn join(self) -> Result<ShutdownSummary, Box<dyn Error>> {
+        Ok(self.task.await??)
+    }
+
+    /// Shuts the server down and waits for it to stop.
+    pub async fn stop(self) -> Result<ShutdownSummary, Box<dyn Error>> {
+        self.shutdown();
+        self.join().await
+    }
 }
 #[derive(Debug)]
 struct TcpStunServer {
-    _server_address: SocketAddr,
     tcp_socket: TcpListener,
+    context: ListenerContext,
 }
 
 #[async_trait]
 impl StunServer for TcpStunServer {
-    async fn run(&self) -> Result<(), Box<dyn Error>> {
-        loop {
-            tokio::select! {
-                tcp_stream = self.tcp_socket.accept() => {
-                    match tcp_stream {
-                        Ok(stream) => {
-                            tokio::spawn(async move {
-                                println!("Accepted connection from {}", &stream.1);
-                                if let Err(e) = handle_tcp_connection(stream.0).await {
-                                    println!("an error occurred; error = {:?}", e);
-                                }
-                            });
-                        },
-                        Err(e) => println!("{:?}", e),
-                    };
-                }
-            }
-        }
+    async fn run(&self, shutdown: Shutdown) -> Result<ShutdownSummary, Box<dyn Error>> {
+        tcp_loop(&self.tcp_socket, &self.context, &shutdown).await
+    }
+
+    fn local_addresses(&self) -> Vec<ListenAddress> {
+        listen_address(self.tcp_socket.local_addr(), StunServerEnum::TcpStunServer)
+    }
+
+    fn stats(&self) -> Option<Arc<ServerStats>> {
+        Some(self.context.stats.clone())
     }
 }
 #[derive(Debug)]
 struct UdpStunServer {
-    _server_address: SocketAddr,
     udp_socket: UdpSocket,
+    context: ListenerContext,
 }
 
 #[async_trait]
 impl StunServer for UdpStunServer {
-    async fn run(&self) -> Result<(), Box<dyn Error>> {
-        loop {
-            let mut buffer = [0 as u8; 1024];
-            tokio::select! {
-                udp_message = self.udp_socket.recv_from(&mut buffer) => {
-                    match udp_message {
-                        Ok(message) => {
-                            let response = handle_udp_connection(&mut buffer, message.0, message.1 ).await?;
-                            self.udp_socket.send(&response).await?;
-                        },
-                        Err(e) => println!("{:?}", e),
-                    }
-                },
-            }
-        }
+    async fn run(&self, shutdown: Shutdown) -> Result<ShutdownSummary, Box<dyn Error>> {
+        udp_loop(&self.udp_socket, &self.context, &shutdown).await
+    }
+
+    fn local_addresses(&self) -> Vec<ListenAddress> {
+        listen_address(self.udp_socket.local_addr(), StunServerEnum::UdpStunServer)
+    }
+
+    fn stats(&self) -> Option<Arc<ServerStats>> {
+        Some(self.context.stats.clone())
     }
 }
 #[derive(Debug)]
 struct MultiplexedStunServer {
-    _server_address: SocketAddr,
     udp_socket: UdpSocket,
     tcp_socket: TcpListener,
+    context: ListenerContext,
 }
 
 #[async_trait]
 impl StunServer for MultiplexedStunServer {
-    async fn run(&self) -> Result<(), Box<dyn Error>> {
-        loop {
-            let mut buffer = [0 as u8; 1024];
-            tokio::select! {
-                udp_message = self.udp_socket.recv_from(&mut buffer) => {
-                    match udp_message {
-                        Ok(message) => {
-                            let response = handle_udp_connection(&mut buffer, message.0, message.1 ).await?;
-                            self.udp_socket.send_to(&response, message.1).await?;
-                        },
-                        Err(e) => println!("{:?}", e),
+    async fn run(&self, shutdown: Shutdown) -> Result<ShutdownSummary, Box<dyn Error>> {
+        let shutdown = shutdown.child();
+        run_all(
+            vec![
+                Box::pin(udp_loop(&self.udp_socket, &self.context, &shutdown)),
+                Box::pin(tcp_loop(&self.tcp_socket, &self.context, &shutdown)),
+            ],
+            &shutdown,
+        )
+        .await
+    }
+
+    fn local_addresses(&self) -> Vec<ListenAddress> {
+        listen_address(
+            self.udp_socket.local_addr(),
+            StunServerEnum::MultiplexedStunServer,
+        )
+    }
+
+    fn stats(&self) -> Option<Arc<ServerStats>> {
+        Some(self.context.stats.clone())
+    }
+}
+
+struct TlsStunServer {
+    tcp_socket: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    context: ListenerContext,
+}
+
+#[async_trait]
+impl StunServer for TlsStunServer {
+    async fn run(&self, shutdown: Shutdown) -> Result<ShutdownSummary, Box<dyn Error>> {
+        accept_loop(
+            &self.tcp_socket,
+            &self.context,
+            &shutdown,
+            |stream, peer| {
+                let tls_acceptor = self.tls_acceptor.clone();
+                let context = self.context.clone();
+                let shutdown = shutdown.clone();
+                async move {
+                    let _connection = metrics::connection("tls");
+                    debug!(%peer, "accepted TLS connection");
+                    let result =
+                        handle_tls_connection(stream, tls_acceptor, &context, &shutdown).await;
+                    if let Err(e) = result {
+                        warn!(%peer, error = %e, "TLS connection failed");
                     }
-                },
-                tcp_stream = self.tcp_socket.accept() => {
-                    match tcp_stream {
-                        Ok(mut stream) => {
-                            tokio::spawn(async move {
-                                println!("Accepted connection from {}", &stream.1);
-                                if let Err(e) = handle_tcp_connection(stream.0).await {
-                                    println!("an error occurred; error = {:?}", e);
-                                }
-                            });
-                        },
-                        Err(e) => println!("{:?}", e),
-                    };
-                },
+                }
+            },
+        )
+        .await
+    }
+
+    fn local_addresses(&self) -> Vec<ListenAddress> {
+        listen_address(self.tcp_socket.local_addr(), StunServerEnum::TlsStunServer)
+    }
+
+    fn stats(&self) -> Option<Arc<ServerStats>> {
+        Some(self.context.stats.clone())
+    }
+}
+
+fn listen_address(
+    address: std::io::Result<SocketAddr>,
+    server_type: StunServerEnum,
+) -> Vec<ListenAddress> {
+    address
+        .map(|address| ListenAddress {
+            address,
+            server_type,
+        })
+        .into_iter()
+        .collect()
+}
+
+async fn tcp_loop(
+    tcp_socket: &TcpListener,
+    context: &ListenerContext,
+    shutdown: &Shutdown,
+) -> Result<ShutdownSummary, Box<dyn Error>> {
+    accept_loop(tcp_socket, context, shutdown, |stream, peer| {
+        let context = context.clone();
+        let shutdown = shutdown.clone();
+        async move {
+            let _connection = metrics::connection("tcp");
+            debug!(%peer, "accepted TCP connection");
+            if let Err(e) = handle_tcp_connection(stream, &context, &shutdown).await {
+                warn!(%peer, error = %e, "TCP connection failed");
             }
         }
+    })
+    .await
+}
+
+/// Accepts connections until `shutdown` is triggered, then drains the open ones.
+async fn accept_loop<F, C>(
+    listener: &TcpListener,
+    context: &ListenerContext,
+    shutdown: &Shutdown,
+    handle: F,
+) -> Result<ShutdownSummary, Box<dyn Error>>
+where
+    F: Fn(TcpStream, SocketAddr) -> C,
+    C: Future<Output = ()> + Send + 'static,
+{
+    let connections = Connections::new();
+    let mut summary = ShutdownSummary::default();
+    loop {
+        tokio::select! {
+            _ = shutdown.triggered() => break,
+            accepted = listener.accept() => match accepted {
+                // Connections from trusted proxies are admitted once the client is known.
+                Ok((_, peer)) if !context.is_trusted_proxy(peer.ip()) && !context.admit(peer.ip()) => {
+                    debug!(%peer, "connection refused, closing");
+                }
+                Ok((stream, peer)) => {
+                    summary.connections += 1;
+                    context.stats.connections.fetch_add(1, Ordering::Relaxed);
+                    connections.spawn(handle(stream, peer));
+                }
+                Err(e) => warn!(error = %e, "accept failed"),
+            },
+        }
     }
+    summary.aborted_connections = connections.drain(shutdown.drain_timeout()).await;
+    Ok(summary)
 }
-#[derive(Debug)]
+
+/// Serves STUN requests on `udp_socket`, one receive/send pair per datagram.
+pub async fn udp_loop_unbatched(
+    udp_socket: &UdpSocket,
+    context: &ListenerContext,
+    shutdown: &Shutdown,
+) -> Result<ShutdownSummary, Box<dyn Error>> {
+    let mut buffer = [0_u8; 1024];
+    let mut response_buffer = Vec::new();
+    let local_address = udp_socket.local_addr()?;
+    let mut summary = ShutdownSummary::default();
+    loop {
+        let received = tokio::select! {
+            _ = shutdown.triggered() => return Ok(summary),
+            received = recv_from(udp_socket, &mut buffer) => received,
+        };
+        match received {
+            Ok((_, address, _)) if !context.admit(address.ip()) => {}
+            Ok((length, address, local)) => {
+                let origin = SocketAddr::new(
+                    local.unwrap_or_else(|| local_address.ip()),
+                    local_address.port(),
+                );
+                let response = handle_udp_connection(
+                    mem::take(&mut response_buffer),
+                    &buffer[..length],
+                    address,
+                    origin,
+                    context,
+                    shutdown,
+                )
+                .await;
+                if let Some(response) = response {
+                    match send_from(udp_socket, &response, address, local).await {
+                        Ok(_) => summary.udp_requests += 1,
+                        Err(e) if is_socket_closed(&e) => return Err(e.into()),
+                        Err(e) => warn!(peer = %address, error = %e, "UDP send failed"),
+                    }
+                    response_buffer = response;
+                }
+            }
+            Err(e) if is_socket_closed(&e) => return Err(e.into()),
+            Err(e) => warn!(error = %e, "UDP receive failed"),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn recv_from(
+    udp_socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    let (length, address) = udp_socket.recv_from(buffer).await?;
+    Ok((length, address, None))
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_from(
+    udp_socket: &UdpSocket,
+    buffer: &[u8],
+    address: SocketAddr,
+    _local: Option<IpAddr>,
+) -> std::io::Result<usize> {
+    udp_socket.send_to(buffer, address).await
+}
+
+/// Policies shared by the loops of a listener.
+#[derive(Debug, Clone)]
+pub struct ListenerContext {
+    pub rate_limiter: Arc<RateLimiter>,
+    pub access: Arc<AccessList>,
+    /// Proxies whose TCP and TLS connections start with a PROXY protocol header. `None`
+    /// when the listener is not behind a proxy.
+    pub trusted_proxies: Option<Arc<Vec<Cidr>>>,
+    /// Middleware and handlers each request goes through.
+    pub pipeline: Arc<Pipeline>,
+    pub stats: Arc<ServerStats>,
+}
+
+impl ListenerContext {
+    /// Whether a datagram or connection from `source` may be handled. The access list is
+    /// checked first, so refused sources do not use up rate limit tokens.
+    pub fn admit(&self, source: IpAddr) -> bool {
+        if !self.access.permits(source) {
+            debug!(%source, "source refused by access list");
+            return false;
+        }
+        self.rate_limiter.admit(source)
+    }
+
+    pub fn is_trusted_proxy(&self, source: IpAddr) -> bool {
+        self.trusted_proxies
+            .as_ref()
+            .is_some_and(|trusted| trusted.iter().any(|cidr| cidr.contains(source)))
+    }
+
+    /// Runs a request through the pipeline, encoding the response into `buffer`.
+    async fn respond(
+        &self,
+        request: &[u8],
+        request_context: &RequestContext,
+        buffer: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        if request.len() >= HEADER_LENGTH {
+            self.stats.requests.fetch_add(1, Ordering::Relaxed);
+        }
+        let response = self
+            .pipeline
+            .handle(request, request_context, buffer)
+            .await?
+            .into_bytes();
+        self.stats.responses.fetch_add(1, Ordering::Relaxed);
+        if Class::from_type(BigEndian::read_u16(&response[0..2])) == Class::ErrorResponse {
+            self.stats.error_responses.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(response)
+    }
+}
+
+impl Default for ListenerContext {
+    /// Everyone is allowed without per-source limits; error responses are capped at the
+    /// default rate. Responses carry no SOFTWARE attribute.
+    fn default() -> Self {
+        let rate_limiter = Arc::new(RateLimiter::new(&RateLimitConfig::default()));
+        ListenerContext {
+            pipeline: StunServerBuilder::new().pipeline(&rate_limiter, None, None, None),
+            rate_limiter,
+            access: Arc::default(),
+            trusted_proxies: None,
+            stats: Arc::default(),
+        }
+    }
+}
+
+/// Runs several listeners side by side on the same runtime. When one fails the others are
+/// shut down.
+struct ListenerGroup {
+    servers: Vec<Box<dyn StunServer>>,
+}
+
+#[async_trait]
+impl StunServer for ListenerGroup {
+    async fn run(&self, shutdown: Shutdown) -> Result<ShutdownSummary, Box<dyn Error>> {
+        let shutdown = shutdown.child();
+        run_all(
+            self.servers
+                .iter()
+                .map(|server| server.run(shutdown.clone()))
+                .collect(),
+            &shutdown,
+        )
+        .await
+    }
+
+    fn local_addresses(&self) -> Vec<ListenAddress> {
+        self.servers
+            .iter()
+            .flat_map(|server| server.local_addresses())
+            .collect()
+    }
+
+    fn stats(&self) -> Option<Arc<ServerStats>> {
+        self.servers.iter().find_map(|server| server.stats())
+    }
+}
+
+/// Binds a UDP socket that reports the destination address of each datagram, so replies
+/// can be sent from the address the client used.
+fn bind_udp_socket(server_address: SocketAddr, only_v6: bool) -> Result<UdpSocket, Box<dyn Error>> {
+    let socket = bind_socket(server_address, Type::DGRAM, only_v6)?;
+    let udp_socket = UdpSocket::from_std(socket.into())?;
+    #[cfg(target_os = "linux")]
+    crate::udp::enable_pktinfo(&udp_socket)?;
+    Ok(udp_socket)
+}
+
+fn bind_tcp_listener(
+    server_address: SocketAddr,
+    only_v6: bool,
+) -> Result<TcpListener, Box<dyn Error>> {
+    let socket = bind_socket(server_address, Type::STREAM, only_v6)?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Creates a non-blocking socket bound to `server_address`. IPv6 sockets are restricted to
+/// IPv6 traffic when `only_v6` is set, so an IPv4 listener can share the port.
+fn bind_socket(server_address: SocketAddr, type_: Type, only_v6: bool) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(server_address), type_, None)?;
+    if server_address.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    if type_ == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&server_address.into())?;
+    Ok(socket)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StunServerEnum {
     TcpStunServer,
     UdpStunServer,
     MultiplexedStunServer,
+    TlsStunServer,
 }
 
-pub struct StunServerBuilder {}
+impl StunServerEnum {
+    /// Maps the protocol names used on the command line (`udp`, `tcp`, `tls`, `multiplex`).
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            "multiplex" => Some(StunServerEnum::MultiplexedStunServer),
+            "tcp" => Some(StunServerEnum::TcpStunServer),
+            "udp" => Some(StunServerEnum::UdpStunServer),
+            "tls" => Some(StunServerEnum::TlsStunServer),
+            _ => None,
+        }
+    }
+}
+
+/// One address to listen on, written as `address:port[/protocol]`, e.g. `0.0.0.0:3478`,
+/// `[::]:3478/udp` or `0.0.0.0:5349/tls`. Without a protocol both UDP and TCP are served.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListenAddress {
+    pub address: SocketAddr,
+    pub server_type: StunServerEnum,
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (address, protocol) = match input.split_once('/') {
+            Some((address, protocol)) => (address, Some(protocol)),
+            None => (input, None),
+        };
+        let address = address
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("invalid listen address {:?}: {}", input, e))?;
+        let server_type = match protocol {
+            Some(protocol) => StunServerEnum::from_protocol(protocol)
+                .ok_or_else(|| format!("unknown protocol {:?} in {:?}", protocol, input))?,
+            None => StunServerEnum::MultiplexedStunServer,
+        };
+        Ok(ListenAddress {
+            address,
+            server_type,
+        })
+    }
+}
+
+/// Builds servers. Requests go through logging, metrics, rate limiting and authentication
+/// (when configured), then the middleware added here in order, then the handler for their
+/// method.
+#[derive(Default)]
+pub struct StunServerBuilder {
+    handlers: BTreeMap<Method, Arc<dyn StunHandler>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    attributes: Arc<AttributeRegistry>,
+    sockets: Vec<PreBound>,
+    authenticator: Option<Arc<Authenticator>>,
+}
+
+/// A socket the application bound itself, served as it is.
+#[derive(Debug)]
+pub enum PreBound {
+    Udp(std::net::UdpSocket),
+    Tcp(std::net::TcpListener),
+    /// Served with the certificate in `Config::tls`.
+    Tls(std::net::TcpListener),
+}
+
+impl PreBound {
+    fn listen_address(&self) -> std::io::Result<ListenAddress> {
+        let (address, server_type) = match self {
+            PreBound::Udp(socket) => (socket.local_addr()?, StunServerEnum::UdpStunServer),
+            PreBound::Tcp(listener) => (listener.local_addr()?, StunServerEnum::TcpStunServer),
+            PreBound::Tls(listener) => (listener.local_addr()?, StunServerEnum::TlsStunServer),
+        };
+        Ok(ListenAddress {
+            address,
+            server_type,
+        })
+    }
+}
 
 impl StunServerBuilder {
+    pub fn new() -> Self {
+        StunServerBuilder::default()
+    }
+
+    /// Answers requests and indications of `method` with `handler`. Registering Binding
+    /// replaces the built-in handling.
+    pub fn handler(mut self, method: Method, handler: impl StunHandler + 'static) -> Self {
+        self.handlers.insert(method, Arc::new(handler));
+        self
+    }
+
+    /// Runs `middleware` after the built-in middleware and any added before it.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Accepts requests carrying the comprehension-required attribute `A` instead of
+    /// answering them with 420 Unknown Attribute.
+    pub fn attribute<A: CustomAttribute>(mut self) -> Self {
+        Arc::make_mut(&mut self.attributes).register::<A>();
+        self
+    }
+
+    /// Serves `socket` next to the listen addresses. Access lists and PROXY protocol
+    /// settings apply to it when keyed by its local address.
+    pub fn socket(mut self, socket: PreBound) -> Self {
+        self.sockets.push(socket);
+        self
+    }
+
+    /// Authenticates requests with `authenticator` instead of one built from `Config::auth`,
+    /// e.g. to keep it for reloading its nonce secrets.
+    pub fn authenticator(mut self, authenticator: Arc<Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Builds the server described by `config` and spawns it on the current runtime. Use
+    /// `bind_config` instead to reload access lists while it runs.
+    pub async fn spawn(self, config: &Config) -> Result<ServerHandle, Box<dyn Error>> {
+        let access = AccessLists::new(config);
+        let server = self.bind_config(config, &access).await?;
+        let shutdown = Shutdown::new(Duration::from_secs(config.shutdown.drain_timeout));
+        Ok(ServerHandle::spawn(server, shutdown))
+    }
+
     pub async fn build(
         server_address: SocketAddr,
         servertype: StunServerEnum,
     ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        StunServerBuilder::build_listener(
+            server_address,
+            servertype,
+            false,
+            None,
+            &ListenerContext::default(),
+        )
+        .await
+    }
+
+    /// Builds the server described by a validated `Config`. Each listener checks sources
+    /// against its list in `access`, which can be reloaded while the server runs.
+    pub async fn from_config(
+        config: &Config,
+        access: &AccessLists,
+    ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        StunServerBuilder::new().bind_config(config, access).await
+    }
+
+    /// Like `from_config`, with the handlers and middleware added to this builder.
+    pub async fn bind_config(
+        mut self,
+        config: &Config,
+        access: &AccessLists,
+    ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        let sockets = mem::take(&mut self.sockets);
+        let tls_acceptor = match &config.tls {
+            Some(tls) => Some(load_acceptor(tls)?),
+            None => None,
+        };
+        let rate_limiter = Arc::new(RateLimiter::new(&config.rate_limit));
+        let redirector = Redirector::new(&config.redirect);
+        let authenticator = match self.authenticator.take() {
+            Some(authenticator) => Some(authenticator),
+            None => Authenticator::new(&config.auth).map(Arc::new),
+        };
+        let software: Option<Arc<str>> = Some(config.software.as_str())
+            .filter(|software| !software.is_empty())
+            .map(Arc::from);
+        let stats = Arc::new(ServerStats::default());
+        let context_for = |listen: &ListenAddress| ListenerContext {
+            rate_limiter: rate_limiter.clone(),
+            access: access.get(listen),
+            trusted_proxies: config
+                .proxy_protocol_for(listen)
+                .map(|proxy_protocol| Arc::new(proxy_protocol.trusted.clone())),
+            pipeline: self.pipeline(
+                &rate_limiter,
+                software.clone(),
+                redirector.as_ref().map(|redirector| {
+                    Arc::new(match listen.server_type {
+                        StunServerEnum::TlsStunServer => redirector.for_tls(),
+                        _ => redirector.clone(),
+                    })
+                }),
+                authenticator.clone(),
+            ),
+            stats: stats.clone(),
+        };
+        let servers = StunServerBuilder::build_listeners(
+            &config.listen,
+            sockets,
+            tls_acceptor.as_ref(),
+            &context_for,
+        )
+        .await?;
+        let mut servers = vec![servers];
+        #[cfg(feature = "metrics")]
+        if let Some(address) = config.metrics.listen {
+            servers.push(Box::new(metrics::MetricsServer::bind(address).await?));
+            info!(%address, "serving metrics");
+        }
+        if let Some(address) = config.health.listen {
+            servers.push(Box::new(HealthServer::bind(address, &config.listen).await?));
+            info!(%address, "serving health checks");
+        }
+        Ok(Box::new(ListenerGroup { servers }))
+    }
+
+    /// Builds one server that listens on every address in `listen_addresses`.
+    ///
+    /// A wildcard IPv6 listener is made IPv6-only when an IPv4 listener uses the same port,
+    /// so `0.0.0.0:3478` and `[::]:3478` can be combined; on its own it stays dual-stack.
+    pub async fn build_all(
+        listen_addresses: &[ListenAddress],
+    ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        StunServerBuilder::new().bind(listen_addresses).await
+    }
+
+    /// Like `build_all`, with the handlers and middleware added to this builder.
+    pub async fn bind(
+        mut self,
+        listen_addresses: &[ListenAddress],
+    ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        let sockets = mem::take(&mut self.sockets);
+        let rate_limiter = Arc::new(RateLimiter::new(&RateLimitConfig::default()));
+        let context = ListenerContext {
+            pipeline: self.pipeline(&rate_limiter, None, None, None),
+            rate_limiter,
+            access: Arc::default(),
+            trusted_proxies: None,
+            stats: Arc::default(),
+        };
+        StunServerBuilder::build_listeners(listen_addresses, sockets, None, &|_| context.clone())
+            .await
+    }
+
+    /// The listeners for `listen_addresses` and the pre-bound `sockets`.
+    async fn build_listeners(
+        listen_addresses: &[ListenAddress],
+        sockets: Vec<PreBound>,
+        tls_acceptor: Option<&TlsAcceptor>,
+        context_for: &(dyn Fn(&ListenAddress) -> ListenerContext + Sync),
+    ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        let bound = sockets
+            .iter()
+            .map(PreBound::listen_address)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        if sockets.is_empty() {
+            return StunServerBuilder::build_group(
+                listen_addresses,
+                &[],
+                tls_acceptor,
+                context_for,
+            )
+            .await;
+        }
+        let mut servers = Vec::with_capacity(sockets.len() + 1);
+        if !listen_addresses.is_empty() {
+            servers.push(
+                StunServerBuilder::build_group(listen_addresses, &bound, tls_acceptor, context_for)
+                    .await?,
+            );
+        }
+        for (socket, listen) in sockets.into_iter().zip(bound) {
+            let context = context_for(&listen);
+            info!(address = %listen.address, transport = ?listen.server_type, "serving pre-bound socket");
+            let server: Box<dyn StunServer> = match socket {
+                PreBound::Udp(socket) => {
+                    socket.set_nonblocking(true)?;
+                    let udp_socket = UdpSocket::from_std(socket)?;
+                    #[cfg(target_os = "linux")]
+                    crate::udp::enable_pktinfo(&udp_socket)?;
+                    Box::new(UdpStunServer {
+                        udp_socket,
+                        context,
+                    })
+                }
+                PreBound::Tcp(listener) => {
+                    listener.set_nonblocking(true)?;
+                    Box::new(TcpStunServer {
+                        tcp_socket: TcpListener::from_std(listener)?,
+                        context,
+                    })
+                }
+                PreBound::Tls(listener) => {
+                    let tls_acceptor = tls_acceptor.ok_or_else(|| {
+                        format!(
+                            "cannot serve {}: TLS listeners need a certificate and private key",
+                            listen.address
+                        )
+                    })?;
+                    listener.set_nonblocking(true)?;
+                    Box::new(TlsStunServer {
+                        tcp_socket: TcpListener::from_std(listener)?,
+                        tls_acceptor: tls_acceptor.clone(),
+                        context,
+                    })
+                }
+            };
+            servers.push(server);
+        }
+        Ok(Box::new(ListenerGroup { servers }))
+    }
+
+    /// The built-in middleware followed by the added middleware and handlers.
+    fn pipeline(
+        &self,
+        rate_limiter: &Arc<RateLimiter>,
+        software: Option<Arc<str>>,
+        redirect: Option<Arc<Redirector>>,
+        auth: Option<Arc<Authenticator>>,
+    ) -> Arc<Pipeline> {
+        let mut middleware: Vec<Arc<dyn Middleware>> = vec![
+            Arc::new(Logging),
+            Arc::new(Metrics),
+            Arc::new(RateLimit::new(rate_limiter.clone())),
+        ];
+        if let Some(auth) = auth {
+            middleware.push(Arc::new(Authentication::new(auth, software.clone())));
+        }
+        middleware.extend(self.middleware.iter().cloned());
+        Arc::new(Pipeline::new(
+            middleware,
+            self.handlers.clone(),
+            software,
+            redirect,
+            self.attributes.clone(),
+        ))
+    }
+
+    /// Builds one listener per address, each with the policies `context_for` returns for it.
+    /// `bound` are the addresses of pre-bound sockets served alongside.
+    async fn build_group(
+        listen_addresses: &[ListenAddress],
+        bound: &[ListenAddress],
+        tls_acceptor: Option<&TlsAcceptor>,
+        context_for: &(dyn Fn(&ListenAddress) -> ListenerContext + Sync),
+    ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        if listen_addresses.is_empty() {
+            return Err("no listen addresses given".into());
+        }
+
+        let mut servers = Vec::with_capacity(listen_addresses.len());
+        for listen in listen_addresses {
+            let only_v6 = listen.address.is_ipv6()
+                && (!listen.address.ip().is_unspecified()
+                    || listen_addresses.iter().chain(bound).any(|other| {
+                        other.address.is_ipv4() && other.address.port() == listen.address.port()
+                    }));
+            servers.push(
+                StunServerBuilder::build_listener(
+                    listen.address,
+                    listen.server_type,
+                    only_v6,
+                    tls_acceptor,
+                    &context_for(listen),
+                )
+                .await?,
+            );
+        }
+        Ok(Box::new(ListenerGroup { servers }))
+    }
+
+    async fn build_listener(
+        server_address: SocketAddr,
+        servertype: StunServerEnum,
+        only_v6: bool,
+        tls_acceptor: Option<&TlsAcceptor>,
+        context: &ListenerContext,
+    ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        let context = context.clone();
+        info!(address = %server_address, transport = ?servertype, "listening");
         match servertype {
             StunServerEnum::TcpStunServer => {
-                return Ok(StunServerBuilder::build_tcp_server(server_address).await?);
+                StunServerBuilder::build_tcp_server(server_address, only_v6, context).await
             }
             StunServerEnum::UdpStunServer => {
-                return Ok(StunServerBuilder::build_udp_server(server_address).await?);
+                StunServerBuilder::build_udp_server(server_address, only_v6, context).await
             }
             StunServerEnum::MultiplexedStunServer => {
-                return Ok(StunServerBuilder::build_multiplexed_server(server_address).await?);
+                StunServerBuilder::build_multiplexed_server(server_address, only_v6, context).await
             }
+            StunServerEnum::TlsStunServer => match tls_acceptor {
+                Some(tls_acceptor) => {
+                    StunServerBuilder::build_tls_server(
+                        server_address,
+                        only_v6,
+                        tls_acceptor.clone(),
+                        context,
+                    )
+                    .await
+                }
+                None => Err(format!(
+                    "cannot listen on {}: TLS listeners need a certificate and private key",
+                    server_address
+                )
+                .into()),
+            },
         }
     }
 
     async fn build_tcp_server(
         server_address: SocketAddr,
+        only_v6: bool,
+        context: ListenerContext,
     ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
-        let tcp_listener = TcpListener::bind(server_address).await?;
+        let tcp_listener = bind_tcp_listener(server_address, only_v6)?;
 
         let tcp_server = TcpStunServer {
-            _server_address: server_address,
             tcp_socket: tcp_listener,
+            context,
         };
 
         Ok(Box::new(tcp_server))
     }
+    async fn build_tls_server(
+        server_address: SocketAddr,
+        only_v6: bool,
+        tls_acceptor: TlsAcceptor,
+        context: ListenerContext,
+    ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
+        let tcp_listener = bind_tcp_listener(server_address, only_v6)?;
+
+        let tls_server = TlsStunServer {
+            tcp_socket: tcp_listener,
+            tls_acceptor,
+            context,
+        };
+
+        Ok(Box::new(tls_server))
+    }
     async fn build_udp_server(
         server_address: SocketAddr,
+        only_v6: bool,
+        context: ListenerContext,
     ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
-        let udp_socket = UdpSocket::bind(server_address).await?;
+        let udp_socket = bind_udp_socket(server_address, only_v6)?;
 
         let udp_server = UdpStunServer {
-            _server_address: server_address,
-            udp_socket: udp_socket,
+            udp_socket,
+            context,
         };
 
         Ok(Box::new(udp_server))
     }
     async fn build_multiplexed_server(
         server_address: SocketAddr,
+        only_v6: bool,
+        context: ListenerContext,
     ) -> Result<Box<dyn StunServer>, Box<dyn Error>> {
-        let udp_socket = UdpSocket::bind(server_address).await?;
-        let tcp_listener = TcpListener::bind(server_address).await?;
+        let udp_socket = bind_udp_socket(server_address, only_v6)?;
+        // With port 0, TCP takes the port the system picked for UDP.
+        let tcp_listener = bind_tcp_listener(udp_socket.local_addr()?, only_v6)?;
 
         let multiplexed_stun_server = MultiplexedStunServer {
-            _server_address: server_address,
             tcp_socket: tcp_listener,
-            udp_socket: udp_socket,
+            udp_socket,
+            context,
         };
         Ok(Box::new(multiplexed_stun_server))
     }
 }
 
-async fn handle_tcp_connection(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0 as u8; 1024];
-    stream.readable().await?;
-    let length = stream.read(&mut buffer).await?;
-    println!("{}", String::from_utf8_lossy(&buffer[..length]));
+async fn handle_tcp_connection(
+    mut stream: TcpStream,
+    context: &ListenerContext,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let addresses = connection_addresses(&mut stream, context).await?;
+    match addresses {
+        Some((peer, local_address)) => {
+            let request_context = RequestContext {
+                peer: canonical_socket_addr(peer),
+                local_address: canonical_socket_addr(local_address),
+                transport: Transport::Tcp,
+                tls: None,
+                shutting_down: shutdown.is_shutting_down(),
+            };
+            handle_stream(stream, &request_context, context).await
+        }
+        None => Ok(()),
+    }
+}
 
-    let message = handle_message(&buffer, stream.peer_addr().unwrap());
+async fn handle_tls_connection(
+    mut stream: TcpStream,
+    tls_acceptor: TlsAcceptor,
+    context: &ListenerContext,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let addresses = connection_addresses(&mut stream, context).await?;
+    match addresses {
+        Some((peer, local_address)) => {
+            let stream = tls_acceptor.accept(stream).await?;
+            let (_, connection) = stream.get_ref();
+            let tls = TlsInfo {
+                server_name: connection.server_name().map(String::from),
+                alpn_protocol: connection.alpn_protocol().map(<[u8]>::to_vec),
+                protocol_version: connection
+                    .protocol_version()
+                    .map(|version| format!("{:?}", version)),
+            };
+            let request_context = RequestContext {
+                peer: canonical_socket_addr(peer),
+                local_address: canonical_socket_addr(local_address),
+                transport: Transport::Tls,
+                tls: Some(Arc::new(tls)),
+                shutting_down: shutdown.is_shutting_down(),
+            };
+            handle_stream(stream, &request_context, context).await
+        }
+        None => Ok(()),
+    }
+}
 
-    let serialized_stun_message = message.serialize();
+/// The client and server address of a connection. Connections from a trusted proxy take them
+/// from the PROXY header, and the client is then checked against the listener's policies;
+/// `None` means it was refused.
+async fn connection_addresses(
+    stream: &mut TcpStream,
+    context: &ListenerContext,
+) -> Result<Option<(SocketAddr, SocketAddr)>, Box<dyn Error>> {
+    let peer_address = stream.peer_addr()?;
+    let local_address = stream.local_addr()?;
+    if !context.is_trusted_proxy(peer_address.ip()) {
+        return Ok(Some((peer_address, local_address)));
+    }
+    let (client, server) = match proxy::read_header(stream).await? {
+        Some(header) => (header.source, header.destination),
+        None => (peer_address, local_address),
+    };
+    debug!(proxy = %peer_address, %client, "read PROXY header");
+    if !context.admit(client.ip()) {
+        return Ok(None);
+    }
+    Ok(Some((client, server)))
+}
 
-    stream.writable().await?;
-    stream.write_all(&serialized_stun_message).await?;
+async fn handle_stream<S>(
+    mut stream: S,
+    request_context: &RequestContext,
+    context: &ListenerContext,
+) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = [0_u8; 1024];
+    let length = stream.read(&mut buffer).await?;
+
+    let response = context
+        .respond(&buffer[..length], request_context, Vec::new())
+        .await;
+    if let Some(response) = response {
+        stream.write_all(&response).await?;
+        stream.flush().await?;
+    }
 
     Ok(())
 }
 
-async fn handle_udp_connection(
-    buffer: &[u8; 1024],
-    message_len: usize,
-    address: SocketAddr,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    println!("Request: {:?}", &buffer[..message_len]);
-    let message = handle_message(&buffer[..message_len], address); //parse address, ta imot address
-    let response = message.serialize();
-    println!("Response: {:?}", &response);
-    Ok(response)
-}
-
-pub fn parse_program_arguments(input: Vec<String>) -> (SocketAddr, StunServerEnum) {
-    match input.len() {
-        1 => {
-            println!("No arguments passed, running on localhost");
-            return (
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 3478),
-                StunServerEnum::MultiplexedStunServer,
-            );
-        }
-        2 => {
-            let parsed_address = &input[1];
-            let address = IpAddr::V4(Ipv4Addr::from_str(parsed_address).unwrap());
-
-            println!("Trying to bind address {}:3478", &parsed_address);
-            return (
-                SocketAddr::new(address, 3478),
-                StunServerEnum::MultiplexedStunServer,
-            );
-        }
-        3 => {
-            let parsed_address = &input[1];
-            let address = IpAddr::V4(Ipv4Addr::from_str(parsed_address).unwrap());
-
-            let parsed_port = &input[2];
-            let port = parsed_port.parse::<u16>().unwrap();
-
-            println!("Trying to bind address {}:{}", &parsed_address, &port);
-            return (
-                SocketAddr::new(address, port),
-                StunServerEnum::MultiplexedStunServer,
-            );
-        }
-        4 => {
-            let parsed_address = &input[1];
-            let address = IpAddr::V4(Ipv4Addr::from_str(parsed_address).unwrap());
+/// Whether `error` means the socket itself is unusable, so its listener has to stop. Other
+/// errors concern one datagram and are only logged.
+pub(crate) fn is_socket_closed(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EBADF) | Some(libc::ENOTSOCK)
+    )
+}
 
-            let parsed_port = &input[2];
-            let port = parsed_port.parse::<u16>().unwrap();
+/// Answers a datagram, encoding the response into `response_buffer`.
+pub(crate) async fn handle_udp_connection(
+    response_buffer: Vec<u8>,
+    buffer: &[u8],
+    address: SocketAddr,
+    local_address: SocketAddr,
+    context: &ListenerContext,
+    shutdown: &Shutdown,
+) -> Option<Vec<u8>> {
+    // Replies still go to `address` as received; the client is told its own family.
+    let request_context = RequestContext {
+        peer: canonical_socket_addr(address),
+        local_address: canonical_socket_addr(local_address),
+        transport: Transport::Udp,
+        tls: None,
+        shutting_down: shutdown.is_shutting_down(),
+    };
+    context
+        .respond(buffer, &request_context, response_buffer)
+        .await
+}
 
-            let parsed_protocol = &input[3];
+/// Parses arguments written as listen addresses (`0.0.0.0:3478 [::]:3478/udp ...`).
+/// Returns `None` when the arguments use the positional `address port protocol` form instead.
+pub fn parse_listen_addresses(input: &[String]) -> Option<Result<Vec<ListenAddress>, String>> {
+    let first = input.get(1)?;
+    if first.split('/').next()?.parse::<SocketAddr>().is_err() {
+        return None;
+    }
+    Some(input[1..].iter().map(|argument| argument.parse()).collect())
+}
 
-            println!(
-                "Trying to bind address {}:{} with protocol: {} ",
-                &parsed_address, &port, &parsed_protocol
-            );
+/// Parses the positional `[address [port [protocol]]]` form used by existing deployments.
+pub fn parse_program_arguments(input: Vec<String>) -> Result<(SocketAddr, StunServerEnum), String> {
+    if input.len() > 4 {
+        return Err(format!(
+            "expected at most 3 positional arguments (address, port, protocol), got {}",
+            input.len() - 1
+        ));
+    }
 
-            match parsed_protocol.as_str() {
-                "multiplex" => {
-                    return (
-                        SocketAddr::new(address, port),
-                        StunServerEnum::MultiplexedStunServer,
-                    );
-                }
-                "tcp" => {
-                    return (
-                        SocketAddr::new(address, port),
-                        StunServerEnum::TcpStunServer,
-                    );
-                }
-                "udp" => {
-                    return (
-                        SocketAddr::new(address, port),
-                        StunServerEnum::UdpStunServer,
-                    );
-                }
-                _ => {
-                    println!("{}, what da fuck even is this??", &parsed_protocol);
-                    return (
-                        SocketAddr::new(address, port),
-                        StunServerEnum::MultiplexedStunServer,
-                    );
-                }
-            }
+    let address = match input.get(1) {
+        Some(parsed_address) => IpAddr::from_str(parsed_address)
+            .map_err(|_| format!("invalid IP address {:?}", parsed_address))?,
+        None => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+    };
+    let port = match input.get(2) {
+        Some(parsed_port) => parsed_port
+            .parse::<u16>()
+            .map_err(|_| format!("invalid port {:?}", parsed_port))?,
+        None => 3478,
+    };
+    let server_type = match input.get(3) {
+        Some(parsed_protocol) => {
+            StunServerEnum::from_protocol(parsed_protocol).ok_or_else(|| {
+                format!(
+                    "unknown protocol {:?}, expected udp, tcp, tls or multiplex",
+                    parsed_protocol
+                )
+            })?
         }
+        None => StunServerEnum::MultiplexedStunServer,
+    };
 
-        _ => {
-            return (
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 3478),
-                StunServerEnum::MultiplexedStunServer,
-            );
-        }
-    }
+    Ok((SocketAddr::new(address, port), server_type))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const stun_message: [u8; 20] = [
+    const STUN_MESSAGE: [u8; 20] = [
         0, 1, 0, 0, 33, 18, 164, 66, 28, 54, 51, 69, 185, 194, 210, 171, 179, 118, 57, 253,
     ];
 
-    const stun_message_success_response: [u8; 44] = [
-        1, 1, 0, 24, 33, 18, 164, 66, 28, 54, 51, 69, 185, 194, 210, 171, 179, 118, 57, 253, 0, 32,
-        0, 8, 0, 1, 58, 43, 94, 18, 164, 67, 0, 1, 0, 8, 0, 1, 27, 57, 127, 0, 0, 1,
+    const STUN_MESSAGE_SUCCESS_RESPONSE: [u8; 56] = [
+        1, 1, 0, 36, 33, 18, 164, 66, 28, 54, 51, 69, 185, 194, 210, 171, 179, 118, 57, 253, 0, 32,
+        0, 8, 0, 1, 58, 43, 94, 18, 164, 67, 0, 1, 0, 8, 0, 1, 27, 57, 127, 0, 0, 1, 128, 43, 0, 8,
+        0, 1, 13, 150, 127, 0, 0, 1,
     ];
 
     #[test]
     fn test_address_inputs() {
-        let parsed = parse_program_arguments(vec!["".to_owned(), "123.123.123.123".to_owned()]);
+        let parsed =
+            parse_program_arguments(vec!["".to_owned(), "123.123.123.123".to_owned()]).unwrap();
         let proper = (
             SocketAddr::new(IpAddr::V4(Ipv4Addr::new(123, 123, 123, 123)), 3478),
             StunServerEnum::MultiplexedStunServer,
@@ -310,7 +1203,8 @@ mod tests {
             "".to_owned(),
             "123.123.123.123".to_owned(),
             "6000".to_owned(),
-        ]);
+        ])
+        .unwrap();
         let proper = (
             SocketAddr::new(IpAddr::V4(Ipv4Addr::new(123, 123, 123, 123)), 6000),
             StunServerEnum::MultiplexedStunServer,
@@ -318,22 +1212,393 @@ mod tests {
         assert_eq!(parsed.0, proper.0);
     }
 
+    #[test]
+    fn test_ipv6_address_inputs() {
+        let parsed = parse_program_arguments(vec!["".to_owned(), "::1".to_owned()]).unwrap();
+        assert_eq!(parsed.0, "[::1]:3478".parse().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_inputs() {
+        let args = |input: &[&str]| input.iter().map(|arg| arg.to_string()).collect();
+        assert!(parse_program_arguments(args(&["", "localhost"])).is_err());
+        assert!(parse_program_arguments(args(&["", "0.0.0.0", "70000"])).is_err());
+        assert!(parse_program_arguments(args(&["", "0.0.0.0", "3478", "sctp"])).is_err());
+        assert!(parse_program_arguments(args(&["", "0.0.0.0", "3478", "udp", "x"])).is_err());
+    }
+
+    #[test]
+    fn test_listen_address_inputs() {
+        let parsed = parse_listen_addresses(&[
+            "".to_owned(),
+            "0.0.0.0:3478".to_owned(),
+            "[::]:3478/udp".to_owned(),
+            "0.0.0.0:5349/tls".to_owned(),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                ListenAddress {
+                    address: "0.0.0.0:3478".parse().unwrap(),
+                    server_type: StunServerEnum::MultiplexedStunServer,
+                },
+                ListenAddress {
+                    address: "[::]:3478".parse().unwrap(),
+                    server_type: StunServerEnum::UdpStunServer,
+                },
+                ListenAddress {
+                    address: "0.0.0.0:5349".parse().unwrap(),
+                    server_type: StunServerEnum::TlsStunServer,
+                },
+            ]
+        );
+        assert!(parse_listen_addresses(&["".to_owned(), "127.0.0.1".to_owned()]).is_none());
+        assert!(
+            parse_listen_addresses(&["".to_owned(), "0.0.0.0:3478/sctp".to_owned()])
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listeners_share_port() {
+        // The IPv4 socket keeps the port taken while the IPv6 listener binds it.
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let listen_addresses: Vec<ListenAddress> =
+            vec![format!("[::]:{}/udp", port).parse().unwrap()];
+        assert!(StunServerBuilder::new()
+            .socket(PreBound::Udp(socket))
+            .bind(&listen_addresses)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_connections() {
+        let server = StunServerBuilder::build(
+            "127.0.0.1:0".parse().unwrap(),
+            StunServerEnum::MultiplexedStunServer,
+        )
+        .await
+        .unwrap();
+        let shutdown = Shutdown::new(std::time::Duration::from_millis(100));
+        let server = ServerHandle::spawn(server, shutdown);
+        let address = server.local_addresses()[0].address;
+
+        crate::client::binding_request(address, crate::client::ClientTransport::Udp)
+            .await
+            .unwrap();
+        // Connected but never sends a request, so it is still open when draining ends.
+        let _idle = TcpStream::connect(address).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let summary = server.stop().await.unwrap();
+        assert_eq!(summary.udp_requests, 1);
+        assert_eq!(summary.connections, 1);
+        assert_eq!(summary.aborted_connections, 1);
+    }
+
+    #[tokio::test]
+    async fn test_access_list_refuses_sources() {
+        let mut config = Config::parse(
+            "listen = [\"127.0.0.1:0\"]\n[access.\"127.0.0.1:0\"]\nallow = [\"10.0.0.0/8\"]\n",
+        )
+        .unwrap();
+        let access = AccessLists::new(&config);
+        let server = StunServerBuilder::from_config(&config, &access)
+            .await
+            .unwrap();
+        let server = ServerHandle::spawn(server, Shutdown::default());
+        let address = server.local_addresses()[0].address;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&STUN_MESSAGE, address).await.unwrap();
+        let mut buffer = [0_u8; 1024];
+        let timeout = std::time::Duration::from_millis(200);
+        assert!(tokio::time::timeout(timeout, socket.recv(&mut buffer))
+            .await
+            .is_err());
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        assert!(matches!(stream.read(&mut buffer).await, Ok(0) | Err(_)));
+
+        config.access.clear();
+        access.reload(&config);
+        socket.send_to(&STUN_MESSAGE, address).await.unwrap();
+        assert!(tokio::time::timeout(timeout, socket.recv(&mut buffer))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_reports_client_address() {
+        use crate::attributes::{decode_xor_address, XOR_MAPPED_ADDRESS};
+        use crate::message::DecodedMessage;
+
+        let config = Config::parse(
+            "listen = [\"127.0.0.1:0/tcp\"]\n[proxy_protocol.\"127.0.0.1:0/tcp\"]\ntrusted = [\"127.0.0.0/8\"]\n",
+        )
+        .unwrap();
+        let server = StunServerBuilder::new().spawn(&config).await.unwrap();
+        let address = server.local_addresses()[0].address;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut request = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 3478\r\n".to_vec();
+        request.extend_from_slice(&STUN_MESSAGE);
+        stream.write_all(&request).await.unwrap();
+        let mut response = vec![0_u8; 1024];
+        let length = stream.read(&mut response).await.unwrap();
+        let message = DecodedMessage::decode(&response[..length]).unwrap();
+        let mapped = message
+            .attribute(XOR_MAPPED_ADDRESS)
+            .and_then(|attribute| decode_xor_address(&attribute.value, &STUN_MESSAGE[8..20]))
+            .unwrap();
+        assert_eq!(mapped, "192.0.2.1:56324".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tls_binding_request() {
+        use crate::config::TlsConfig;
+        use std::convert::TryFrom;
+        use std::sync::Arc;
+        use tokio_rustls::rustls::crypto::ring::default_provider;
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_rustls::TlsConnector;
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let directory = std::env::temp_dir().join(format!("stun-tls-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let tls = TlsConfig {
+            certificate: directory.join("cert.pem"),
+            private_key: directory.join("key.pem"),
+        };
+        std::fs::write(&tls.certificate, certified.cert.pem()).unwrap();
+        std::fs::write(&tls.private_key, certified.key_pair.serialize_pem()).unwrap();
+
+        let config = Config {
+            listen: vec!["127.0.0.1:0/tls".parse().unwrap()],
+            tls: Some(tls),
+            ..Config::default()
+        };
+        let server = StunServerBuilder::new().spawn(&config).await.unwrap();
+        let address = server.local_addresses()[0].address;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        stream.write_all(&STUN_MESSAGE).await.unwrap();
+        let mut response = [0_u8; 1024];
+        let length = stream.read(&mut response).await.unwrap();
+        assert!(length > 20);
+        assert_eq!(&response[..2], &[1, 1]);
+        assert_eq!(&response[8..20], &STUN_MESSAGE[8..20]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_builder_registers_method_handlers() {
+        use crate::message::StunMessage;
+        use crate::pipeline::Request;
+
+        struct Echo;
+
+        #[async_trait]
+        impl StunHandler for Echo {
+            async fn handle(&self, mut request: Request<'_>) -> Option<StunMessage> {
+                Some(
+                    request
+                        .response(Class::SuccessResponse)
+                        .attr(crate::attributes::ResponseOrigin::new(request.context.peer))
+                        .build(),
+                )
+            }
+        }
+
+        let method = Method::new(0x200).unwrap();
+        let config = Config {
+            listen: vec!["127.0.0.1:0/udp".parse().unwrap()],
+            ..Config::default()
+        };
+        let server = StunServerBuilder::new()
+            .handler(method, Echo)
+            .spawn(&config)
+            .await
+            .unwrap();
+        let address = server.local_addresses()[0].address;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = StunMessage::builder(method, Class::Request, [3; 12]).build();
+        socket.send_to(request.as_bytes(), address).await.unwrap();
+        let mut buffer = [0_u8; 1024];
+        let length = socket.recv(&mut buffer).await.unwrap();
+        let response = crate::message::StunMessageRef::parse(&buffer[..length]).unwrap();
+        assert_eq!(response.method(), method);
+        assert_eq!(response.class(), Class::SuccessResponse);
+        assert_eq!(
+            crate::attributes::decode_address(
+                response
+                    .attribute(crate::attributes::RESPONSE_ORIGIN)
+                    .unwrap()
+                    .value
+            ),
+            Some(socket.local_addr().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spawned_server_on_ephemeral_port() {
+        use crate::client::{binding_request, ClientTransport};
+
+        let config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Config::default()
+        };
+        let server = StunServerBuilder::new().spawn(&config).await.unwrap();
+        let address = server.local_addresses()[0].address;
+        assert_ne!(address.port(), 0);
+        assert_eq!(
+            server.local_addresses()[0].server_type,
+            StunServerEnum::MultiplexedStunServer
+        );
+
+        binding_request(address, ClientTransport::Udp)
+            .await
+            .unwrap();
+        binding_request(address, ClientTransport::Tcp)
+            .await
+            .unwrap();
+        assert_eq!(
+            server.stats(),
+            Stats {
+                requests: 2,
+                responses: 2,
+                error_responses: 0,
+                connections: 1,
+            }
+        );
+        let summary = server.stop().await.unwrap();
+        assert_eq!(summary.udp_requests, 1);
+        assert_eq!(summary.connections, 1);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listener_reports_ipv4_clients_as_ipv4() {
+        use crate::client::{binding_request, ClientTransport};
+
+        let config = Config {
+            listen: vec!["[::]:0".parse().unwrap()],
+            ..Config::default()
+        };
+        let server = StunServerBuilder::new().spawn(&config).await.unwrap();
+        let port = server.local_addresses()[0].address.port();
+        let address: SocketAddr = ([127, 0, 0, 1], port).into();
+        for &transport in &[ClientTransport::Udp, ClientTransport::Tcp] {
+            let response = binding_request(address, transport).await.unwrap();
+            assert!(response.mapped_address.is_ipv4(), "{:?}", transport);
+            assert_eq!(response.response_origin, Some(address));
+        }
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_redirects_once_shutdown_begins() {
+        let config = Config {
+            listen: vec!["127.0.0.1:0/udp".parse().unwrap()],
+            redirect: crate::config::RedirectConfig {
+                servers: vec!["198.51.100.2:3478".parse().unwrap()],
+                on_shutdown: true,
+                unauthenticated: true,
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let server = StunServerBuilder::new().spawn(&config).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .connect(server.local_addresses()[0].address)
+            .await
+            .unwrap();
+        async fn request(client: &tokio::net::UdpSocket) -> Class {
+            let mut response = [0_u8; 1024];
+            client.send(&STUN_MESSAGE).await.unwrap();
+            client.recv(&mut response).await.unwrap();
+            Class::from_type(BigEndian::read_u16(&response[0..2]))
+        }
+
+        assert_eq!(request(&client).await, Class::SuccessResponse);
+        server.begin_shutdown();
+        assert_eq!(request(&client).await, Class::ErrorResponse);
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pre_bound_sockets() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let server = StunServerBuilder::new()
+            .socket(PreBound::Udp(socket))
+            .bind(&[])
+            .await
+            .unwrap();
+        assert_eq!(
+            server.local_addresses(),
+            vec![ListenAddress {
+                address,
+                server_type: StunServerEnum::UdpStunServer,
+            }]
+        );
+        let server = ServerHandle::spawn(server, Shutdown::default());
+        crate::client::binding_request(address, crate::client::ClientTransport::Udp)
+            .await
+            .unwrap();
+        assert_eq!(server.stop().await.unwrap().udp_requests, 1);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(StunServerBuilder::new()
+            .socket(PreBound::Tls(listener))
+            .bind(&[])
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_udp_message_handling() {
         // buffer.write_all(stun_message_success_response);
 
-        let mut buffer = [0 as u8; 1024];
-        for i in 0..20 {
-            buffer[i] = stun_message[i];
-        }
+        let mut buffer = [0_u8; 1024];
+        buffer[..20].copy_from_slice(&STUN_MESSAGE);
 
         let address: SocketAddr = "127.0.0.1:6969".parse().unwrap();
 
-        let handled_request = handle_udp_connection(&buffer, buffer.len(), address);
+        let local_address: SocketAddr = "127.0.0.1:3478".parse().unwrap();
+
+        let context = ListenerContext::default();
+        let shutdown = Shutdown::default();
+        let handled_request = handle_udp_connection(
+            Vec::new(),
+            &buffer,
+            address,
+            local_address,
+            &context,
+            &shutdown,
+        );
 
         assert_eq!(
             handled_request.await.unwrap(),
-            stun_message_success_response
+            STUN_MESSAGE_SUCCESS_RESPONSE
         );
     }
 }
@@ -0,0 +1,70 @@
+//! TLS for the `tls` transport (STUN over TLS, RFC 5389 section 7.2.2).
+use crate::config::TlsConfig;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Where distributions keep their bundle of trusted CA certificates.
+const SYSTEM_ROOTS: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// Loads the certificate chain and private key named in `config`.
+pub fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(open(&config.certificate)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(format!("no certificates found in {}", config.certificate.display()).into());
+    }
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(open(&config.private_key)?))?
+        .ok_or_else(|| format!("no private key found in {}", config.private_key.display()))?;
+
+    let server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Loads the trusted CA certificates in the PEM file at `path`.
+pub fn load_roots(path: &Path) -> Result<RootCertStore, Box<dyn Error>> {
+    let mut roots = RootCertStore::empty();
+    for certificate in rustls_pemfile::certs(&mut BufReader::new(open(path)?)) {
+        roots.add(certificate?)?;
+    }
+    if roots.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()).into());
+    }
+    Ok(roots)
+}
+
+/// Loads the system's trusted CA certificates from the first bundle found.
+pub fn system_roots() -> Result<RootCertStore, Box<dyn Error>> {
+    SYSTEM_ROOTS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+        .ok_or("no system CA certificates found, pass a CA file")
+        .map_err(Box::<dyn Error>::from)
+        .and_then(load_roots)
+}
+
+/// A client that trusts `roots`.
+pub fn connector(roots: RootCertStore) -> Result<TlsConnector, Box<dyn Error>> {
+    let client_config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+fn open(path: &Path) -> Result<File, Box<dyn Error>> {
+    File::open(path).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
@@ -0,0 +1,329 @@
+//! Linux UDP socket helpers.
+//!
+//! A socket bound to a wildcard address can receive datagrams on any local IP, but a plain
+//! `send_to` lets the kernel pick the source address of the reply. On multi-homed hosts that
+//! is not always the address the client sent to, and NATs drop the reply. With `IP_PKTINFO` /
+//! `IPV6_RECVPKTINFO` enabled the kernel reports the destination address of every datagram,
+//! and the same control message on send pins the source address of the reply.
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+/// Room for one `in_pktinfo` or `in6_pktinfo` control message.
+pub const CONTROL_SIZE: usize = 64;
+
+#[repr(C, align(8))]
+#[derive(Clone, Copy)]
+pub struct ControlBuffer(pub [u8; CONTROL_SIZE]);
+
+impl ControlBuffer {
+    pub fn new() -> Self {
+        ControlBuffer([0_u8; CONTROL_SIZE])
+    }
+}
+
+impl Default for ControlBuffer {
+    fn default() -> Self {
+        ControlBuffer::new()
+    }
+}
+
+/// Asks the kernel to report the local destination address of each received datagram.
+pub fn enable_pktinfo(socket: &UdpSocket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => set_option(fd, libc::IPPROTO_IP, libc::IP_PKTINFO),
+        SocketAddr::V6(_) => {
+            set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)?;
+            // Dual-stack sockets also receive IPv4 traffic; not fatal if the kernel refuses.
+            let _ = set_option(fd, libc::IPPROTO_IP, libc::IP_PKTINFO);
+            Ok(())
+        }
+    }
+}
+
+/// Receives one datagram. Returns its length, the peer address and the local address the
+/// datagram was sent to, if the kernel reported it.
+pub async fn recv_from(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    let fd = socket.as_raw_fd();
+    socket
+        .async_io(Interest::READABLE, || recvmsg(fd, buffer))
+        .await
+}
+
+/// Sends `buffer` to `peer`, using `local` as the source address when it is known.
+pub async fn send_from(
+    socket: &UdpSocket,
+    buffer: &[u8],
+    peer: SocketAddr,
+    local: Option<IpAddr>,
+) -> io::Result<usize> {
+    let fd = socket.as_raw_fd();
+    socket
+        .async_io(Interest::WRITABLE, || sendmsg(fd, buffer, peer, local))
+        .await
+}
+
+fn recvmsg(fd: RawFd, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut control = ControlBuffer::new();
+    let mut iovec = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    prepare_header(&mut header, &mut address, &mut iovec, &mut control);
+
+    let received = unsafe { libc::recvmsg(fd, &mut header, libc::MSG_DONTWAIT) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let peer = to_socket_addr(&address)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unsupported address family"))?;
+    Ok((received as usize, peer, read_pktinfo(&header)))
+}
+
+fn sendmsg(fd: RawFd, buffer: &[u8], peer: SocketAddr, local: Option<IpAddr>) -> io::Result<usize> {
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut control = ControlBuffer::new();
+    let mut iovec = libc::iovec {
+        iov_base: buffer.as_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    prepare_header(&mut header, &mut address, &mut iovec, &mut control);
+    header.msg_namelen = from_socket_addr(peer, &mut address);
+    write_pktinfo(&mut header, peer, local);
+
+    let sent = unsafe { libc::sendmsg(fd, &header, libc::MSG_DONTWAIT) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Points `header` at the given address, payload and control storage.
+pub(crate) fn prepare_header(
+    header: &mut libc::msghdr,
+    address: &mut libc::sockaddr_storage,
+    iovec: &mut libc::iovec,
+    control: &mut ControlBuffer,
+) {
+    header.msg_name = address as *mut _ as *mut libc::c_void;
+    header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    header.msg_iov = iovec;
+    header.msg_iovlen = 1;
+    header.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+    header.msg_controllen = CONTROL_SIZE as _;
+}
+
+/// Extracts the destination address from an `IP_PKTINFO` or `IPV6_PKTINFO` control message.
+pub(crate) fn read_pktinfo(header: &libc::msghdr) -> Option<IpAddr> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(header) };
+    while !cmsg.is_null() {
+        let message = unsafe { &*cmsg };
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+        match (message.cmsg_level, message.cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = unsafe { ptr::read_unaligned(data as *const libc::in_pktinfo) };
+                return Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    info.ipi_addr.s_addr,
+                ))));
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = unsafe { ptr::read_unaligned(data as *const libc::in6_pktinfo) };
+                return Some(unmap(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(header, cmsg) };
+    }
+    None
+}
+
+/// Whether a reply can be sent from `address`. Datagrams sent to a multicast or broadcast
+/// address report it as their destination, but it is not a valid source.
+fn is_unicast(address: IpAddr) -> bool {
+    let address = match address {
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => IpAddr::V4(address),
+            None => IpAddr::V6(address),
+        },
+        address => address,
+    };
+    let broadcast = match address {
+        IpAddr::V4(address) => address.is_broadcast(),
+        IpAddr::V6(_) => false,
+    };
+    !address.is_multicast() && !address.is_unspecified() && !broadcast
+}
+
+/// Fills the control buffer of `header` so the datagram leaves from `local`, or clears it
+/// when the local address is unknown or not a unicast address. Directed subnet broadcasts
+/// cannot be told apart here and are left to the kernel.
+pub(crate) fn write_pktinfo(header: &mut libc::msghdr, peer: SocketAddr, local: Option<IpAddr>) {
+    let local = match local.filter(|local| is_unicast(*local)) {
+        Some(local) => local,
+        None => {
+            header.msg_control = ptr::null_mut();
+            header.msg_controllen = 0;
+            return;
+        }
+    };
+
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(header) };
+    let message = unsafe { &mut *cmsg };
+    let data = unsafe { libc::CMSG_DATA(cmsg) };
+    let space = match (peer, local) {
+        (SocketAddr::V4(_), IpAddr::V4(local)) => {
+            let mut info: libc::in_pktinfo = unsafe { mem::zeroed() };
+            info.ipi_spec_dst.s_addr = u32::from(local).to_be();
+            message.cmsg_level = libc::IPPROTO_IP;
+            message.cmsg_type = libc::IP_PKTINFO;
+            message.cmsg_len = unsafe { libc::CMSG_LEN(mem::size_of_val(&info) as u32) } as _;
+            unsafe { ptr::write_unaligned(data as *mut libc::in_pktinfo, info) };
+            unsafe { libc::CMSG_SPACE(mem::size_of_val(&info) as u32) }
+        }
+        (SocketAddr::V6(_), local) => {
+            let local = match local {
+                IpAddr::V4(local) => local.to_ipv6_mapped(),
+                IpAddr::V6(local) => local,
+            };
+            let mut info: libc::in6_pktinfo = unsafe { mem::zeroed() };
+            info.ipi6_addr.s6_addr = local.octets();
+            message.cmsg_level = libc::IPPROTO_IPV6;
+            message.cmsg_type = libc::IPV6_PKTINFO;
+            message.cmsg_len = unsafe { libc::CMSG_LEN(mem::size_of_val(&info) as u32) } as _;
+            unsafe { ptr::write_unaligned(data as *mut libc::in6_pktinfo, info) };
+            unsafe { libc::CMSG_SPACE(mem::size_of_val(&info) as u32) }
+        }
+        (SocketAddr::V4(_), IpAddr::V6(_)) => 0,
+    };
+    if space == 0 {
+        header.msg_control = ptr::null_mut();
+    }
+    header.msg_controllen = space as _;
+}
+
+pub(crate) fn to_socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let address = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+                u16::from_be(address.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let address = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(address.sin6_addr.s6_addr),
+                u16::from_be(address.sin6_port),
+                address.sin6_flowinfo,
+                address.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn from_socket_addr(
+    address: SocketAddr,
+    storage: &mut libc::sockaddr_storage,
+) -> libc::socklen_t {
+    match address {
+        SocketAddr::V4(address) => {
+            let sockaddr = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = address.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from(*address.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(address) => {
+            let sockaddr = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = address.port().to_be();
+            sockaddr.sin6_addr.s6_addr = address.ip().octets();
+            sockaddr.sin6_flowinfo = address.flowinfo();
+            sockaddr.sin6_scope_id = address.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+/// Reports IPv4-mapped IPv6 addresses (dual-stack sockets) as plain IPv4.
+fn unmap(address: Ipv6Addr) -> IpAddr {
+    match address.to_ipv4_mapped() {
+        Some(address) => IpAddr::V4(address),
+        None => IpAddr::V6(address),
+    }
+}
+
+fn set_option(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &enable as *const _ as *const libc::c_void,
+            mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_addr_round_trip() {
+        for address in &["192.0.2.1:32853", "[2001:db8::1]:3478"] {
+            let address: SocketAddr = address.parse().unwrap();
+            let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+            from_socket_addr(address, &mut storage);
+            assert_eq!(to_socket_addr(&storage), Some(address));
+        }
+    }
+
+    #[test]
+    fn test_only_unicast_sources_are_pinned() {
+        assert!(is_unicast("192.0.2.1".parse().unwrap()));
+        assert!(is_unicast("2001:db8::1".parse().unwrap()));
+        assert!(!is_unicast("255.255.255.255".parse().unwrap()));
+        assert!(!is_unicast("224.0.0.1".parse().unwrap()));
+        assert!(!is_unicast("::ffff:239.1.1.1".parse().unwrap()));
+        assert!(!is_unicast("ff02::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_reports_destination_address() {
+        let server = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        enable_pktinfo(&server).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", ("127.0.0.1", port)).await.unwrap();
+
+        let mut buffer = [0_u8; 16];
+        let (length, peer, local) = recv_from(&server, &mut buffer).await.unwrap();
+        assert_eq!(&buffer[..length], b"ping");
+        assert_eq!(peer, client.local_addr().unwrap());
+        assert_eq!(local, Some("127.0.0.1".parse().unwrap()));
+
+        send_from(&server, b"pong", peer, local).await.unwrap();
+        let (length, from) = client.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..length], b"pong");
+        assert_eq!(from, SocketAddr::new(local.unwrap(), port));
+    }
+}